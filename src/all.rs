@@ -1,10 +1,19 @@
-use std::fs::{self, DirEntry};
-use std::io;
+mod config;
+mod exit_code;
+mod report;
 
-use std::path::Path;
+use config::Config;
+use report::Counts;
+use std::collections::HashMap;
+use std::fs::{self, DirEntry};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 // one possible implementation of walking a directory only visiting files
-fn visit_dirs(dir: &Path, cb: &dyn Fn(&DirEntry)) -> io::Result<()> {
+fn visit_dirs(dir: &Path, cb: &mut dyn FnMut(&DirEntry)) -> io::Result<()> {
     if dir.is_dir() {
         for entry in fs::read_dir(dir)? {
             let entry = entry?;
@@ -19,102 +28,241 @@ fn visit_dirs(dir: &Path, cb: &dyn Fn(&DirEntry)) -> io::Result<()> {
     Ok(())
 }
 
+/// How often `--watch` polls the suite directories.
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
 fn main() {
+    let mut config_path = None;
+    let mut watch_mode = false;
+    let mut rerun_failing_on_rebuild = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--watch" => watch_mode = true,
+            "--rerun-failing-on-rebuild" => rerun_failing_on_rebuild = true,
+            // `run_one` picks the bless mode up from the environment.
+            "--strict" => std::env::set_var("CRANELIFT_TEST_STRICT", "1"),
+            "--bless" => std::env::set_var("CRANELIFT_TEST_BLESS", "1"),
+            _ if arg.starts_with("--bless-source=") => {
+                std::env::set_var("CRANELIFT_BLESS_SOURCE", &arg["--bless-source=".len()..])
+            }
+            _ if arg.starts_with("--bless=") => {
+                std::env::set_var("CRANELIFT_TEST_BLESS", &arg["--bless=".len()..])
+            }
+            "--config" => match args.next() {
+                Some(path) => config_path = Some(PathBuf::from(path)),
+                None => {
+                    eprintln!("--config requires a path");
+                    std::process::exit(exit_code::OTHER);
+                }
+            },
+            _ => {
+                eprintln!("unknown argument `{}`", arg);
+                std::process::exit(exit_code::OTHER);
+            }
+        }
+    }
+    let config = match Config::load(config_path.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(exit_code::OTHER);
+        }
+    };
+    if let Some(dir) = &config.compile_cache {
+        // Picked up by `run_one` from the environment.
+        std::env::set_var("CRANELIFT_COMPILE_CACHE", dir);
+    }
     {
         let mut x = std::process::Command::new("cargo");
         x.arg("build");
 
         let output = x.output().unwrap();
         println!("{}", String::from_utf8_lossy(&output.stdout[..]));
-        println!("{}", String::from_utf8_lossy(&output.stdout[..]));
+        println!("{}", String::from_utf8_lossy(&output.stderr[..]));
         if !output.status.success() {
             std::process::exit(output.status.code().unwrap());
         }
     }
-    let run = || {
-        let dir = String::from("../wasmtime/cranelift/filetests/filetests/runtests/");
-        let files = vec![
-            "alias.clif",
-            "arithmetic.clif",
-            "atomic-cas.clif",
-            "bint.clif",
-            "br_table.clif",
-            "const.clif",
-            "div-checks.clif",
-            "i128-bint.clif",
-            "i128-bitops.clif",
-            // "i128-br.clif", not pass
-            "i128-bornot.clif",
-            "icmp-eq.clif",
-            "icmp-ne.clif",
-            "icmp-sge.clif",
-            "icmp-sgt.clif",
-            "icmp-sle.clif",
-            "icmp-slt.clif",
-            "icmp-uge.clif",
-            "icmp-ugt.clif",
-            "icmp-ule.clif",
-            "icmp-ult.clif",
-            "umulhi.clif",
-            "i128-br.clif",
-            "i128-bricmp.clif",
-        ];
-        let mut not_ok = vec![];
-        for f in files {
-            let mut cmd = std::process::Command::new("./target/debug/run_one");
-            let mut path = dir.clone();
-            path.push_str(f);
-            cmd.arg(path.as_str());
-            let output = cmd.output().unwrap();
-            println!("{}", String::from_utf8_lossy(&output.stdout[..]));
-            println!("{}", String::from_utf8_lossy(&output.stdout[..]));
-            let code = output.status.code().unwrap();
-
-            if code != 0 {
-                println!("test no ok for {} , code : {}", f, code);
-                // std::process::exit(code);
+
+    let files = collect_files(&config);
+    let (_, not_ok) = run_files(&config, &files);
+    println!("not oks{:?}", not_ok);
+    if watch_mode {
+        watch(&config, not_ok, rerun_failing_on_rebuild);
+    }
+    if !not_ok.is_empty() {
+        std::process::exit(exit_code::OTHER);
+    }
+}
+
+/// All test files selected by `config`, sorted.
+fn collect_files(config: &Config) -> Vec<PathBuf> {
+    let mut files = vec![];
+    for dir in config.suite_dirs() {
+        visit_dirs(&dir, &mut |entry: &DirEntry| {
+            if config.selects(&entry.path()) {
+                files.push(entry.path());
+            }
+        })
+        .unwrap();
+    }
+    files.sort();
+    files
+}
+
+/// Run every file in `files` with every flag set of `config`, returning the files that were run
+/// and those of them that failed. With `fail_fast`, the files after the first failure aren't run.
+fn run_files(config: &Config, files: &[PathBuf]) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let mut ran = vec![];
+    let mut not_ok = vec![];
+    let mut totals = Counts::default();
+    'files: for path in files {
+        ran.push(path.clone());
+        for flags in &config.flags {
+            let (code, counts) = run_one(config, path, flags);
+            if let Some(counts) = counts {
+                totals.add(counts);
+            }
+            if code != exit_code::SUCCESS {
+                println!(
+                    "test no ok for {} {:?} , code : {} ({})",
+                    path.display(),
+                    flags,
+                    code,
+                    exit_code::describe(code)
+                );
                 not_ok.push(path.clone());
+                if config.fail_fast {
+                    break 'files;
+                }
+                continue 'files;
             }
         }
-        println!("not oks{:?}", not_ok);
+    }
+    println!(
+        "{} files: {} passed, {} failed, {} skipped",
+        ran.len(),
+        totals.passed,
+        totals.failed,
+        totals.skipped
+    );
+    (ran, not_ok)
+}
+
+/// Poll the suite directories forever, rerunning `.clif` files as they change.
+///
+/// With `rerun_failing_on_rebuild`, the files that are currently failing are also rerun
+/// whenever the `run_one` binary changes. Files left unrun by `fail_fast` stay queued for the
+/// next round.
+fn watch(config: &Config, mut failing: Vec<PathBuf>, rerun_failing_on_rebuild: bool) -> ! {
+    let snapshot = || -> HashMap<PathBuf, Option<SystemTime>> {
+        collect_files(config)
+            .into_iter()
+            .map(|path| {
+                let modified = modified(&path);
+                (path, modified)
+            })
+            .collect()
     };
-    let out = || {
-        visit_dirs(
-            &Path::new("../wasmtime/cranelift/filetests/filetests/isa/riscv64"),
-            &|entry: &DirEntry| {
-                if entry.file_type().unwrap().is_dir() {
-                    return;
-                }
-                if !entry.file_name().to_str().unwrap().ends_with(".clif") {
-                    return;
-                }
-                if entry.path().to_str().unwrap().contains("elf-gd") {
-                    // remove me.
-                    return;
-                }
-                println!("{:?}", &entry);
-                let mut cmd = std::process::Command::new("./target/debug/run_one");
-                cmd.arg(entry.path().to_str().unwrap());
-                let output = cmd.output().unwrap();
-                println!("{}", String::from_utf8_lossy(&output.stdout[..]));
-                println!("{}", String::from_utf8_lossy(&output.stdout[..]));
-                let code = output.status.code().unwrap();
-
-                if code != 0 {
-                    // println!(
-                    //     "!!!!!!!!!!!!!!!test no ok for {:?} , code : {}",
-                    //     entry.path().to_str(),
-                    //     code
-                    // );
-                    // std::process::exit(code);
-                    panic!("{:?}", entry.path().to_str());
-                }
-            },
-        )
-        .unwrap();
+    let mut files = snapshot();
+    let mut runner = modified(&config.runner);
+    let mut to_run = Vec::new();
+    println!("watching {:?} for changes", config.suite_dirs());
+    loop {
+        thread::sleep(WATCH_INTERVAL);
+
+        let now = snapshot();
+        to_run.extend(
+            now.iter()
+                .filter(|(path, modified)| files.get(*path) != Some(*modified))
+                .map(|(path, _)| path.clone()),
+        );
+        files = now;
+
+        let now = modified(&config.runner);
+        if now != runner {
+            runner = now;
+            if rerun_failing_on_rebuild {
+                println!("{} was rebuilt", config.runner.display());
+                to_run.extend(failing.iter().cloned());
+            }
+        }
+
+        to_run.sort();
+        to_run.dedup();
+        if to_run.is_empty() {
+            continue;
+        }
+        let (ran, not_ok) = run_files(config, &to_run);
+        to_run.retain(|path| !ran.contains(path));
+        failing.retain(|path| !ran.contains(path));
+        failing.extend(not_ok);
+        failing.sort();
+        println!("not oks{:?}", failing);
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Run `run_one` on the test file at `path` with the Cranelift settings `flags`, and return its
+/// exit code along with the test counts it reported.
+fn run_one(config: &Config, path: &Path, flags: &[String]) -> (i32, Option<Counts>) {
+    println!("{:?} {:?}", path, flags);
+    let mut cmd = Command::new(&config.runner);
+    cmd.arg("--emulator").arg(config.emulator.name());
+    for flag in flags {
+        cmd.arg("--set").arg(flag);
+    }
+    cmd.arg(path);
+    let (output, timed_out) = run_with_timeout(&mut cmd, config.timeout()).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout[..]);
+    println!("{}", stdout);
+    println!("{}", String::from_utf8_lossy(&output.stderr[..]));
+    let counts = Counts::parse(&stdout);
+    if timed_out {
+        return (exit_code::TIMEOUT, counts);
+    }
+    // Killed by a signal.
+    (output.status.code().unwrap_or(exit_code::OTHER), counts)
+}
+
+/// Run `cmd` to completion, killing it once `timeout` has passed. The returned flag tells
+/// whether it was killed.
+fn run_with_timeout(cmd: &mut Command, timeout: Option<Duration>) -> io::Result<(Output, bool)> {
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    // Drain the pipes while waiting so a chatty child can't block on a full pipe.
+    let read_all = |mut pipe: Box<dyn Read + Send>| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = pipe.read_to_end(&mut buf);
+            buf
+        })
+    };
+    let stdout = read_all(Box::new(child.stdout.take().unwrap()));
+    let stderr = read_all(Box::new(child.stderr.take().unwrap()));
+
+    let started = Instant::now();
+    let mut timed_out = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if timeout.map_or(false, |t| started.elapsed() >= t) {
+            child.kill()?;
+            timed_out = true;
+            break child.wait()?;
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    let output = Output {
+        status,
+        stdout: stdout.join().unwrap(),
+        stderr: stderr.join().unwrap(),
     };
-    out();
-    // run();
+    Ok((output, timed_out))
 }
 
 #[test]
@@ -0,0 +1,153 @@
+//! Optional on-disk cache of compiled functions.
+//!
+//! When a cache directory is set with `--compile-cache` or [CACHE_ENV] (see
+//! [crate::runone::Options]), [crate::compiler::compile2] looks functions up there before compiling them. Entries are keyed by a hash of the printed
+//! IR, the ISA and its flags, and the backend revision, so any change to one of those is a miss.
+//! Each entry is stored as three files: `<key>.bin` with the machine code, `<key>.s` with the
+//! disassembly and `<key>.relocs` with one relocation per line.
+//!
+//! The backend revision comes from [REVISION_ENV] if set. Otherwise it is derived from the
+//! runner executable itself, which links the backend statically: rebuilding the runner after
+//! touching Cranelift invalidates the cache even if the changes aren't committed.
+
+use crate::compiler::isa_description;
+use cranelift_codegen::ir::Function;
+use cranelift_codegen::isa::TargetIsa;
+use log::{debug, warn};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+/// Environment variable with the cache directory when no `--compile-cache` flag is given.
+pub const CACHE_ENV: &str = "CRANELIFT_COMPILE_CACHE";
+
+/// Environment variable overriding the backend revision part of the cache key.
+pub const REVISION_ENV: &str = "CRANELIFT_BACKEND_REVISION";
+
+/// The backend revision, computed once.
+static REVISION: Mutex<Option<String>> = Mutex::new(None);
+
+/// A compiled function as stored in the cache.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub code: Vec<u8>,
+    pub disasm: String,
+    pub relocs: Vec<Reloc>,
+}
+
+/// A relocation in [Entry::code], kept in its printed form.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reloc {
+    pub offset: u32,
+    pub kind: String,
+    pub name: String,
+    pub addend: i64,
+}
+
+/// The cache key of `function` compiled for `isa`.
+pub fn key(function: &Function, isa: &dyn TargetIsa) -> String {
+    let mut hasher = DefaultHasher::new();
+    function.display().to_string().hash(&mut hasher);
+    isa_description(isa).hash(&mut hasher);
+    revision().hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Look `key` up in the cache in `dir`, treating unreadable entries as misses.
+pub fn load(dir: &Path, key: &str) -> Option<Entry> {
+    let path = |ext| dir.join(key).with_extension(ext);
+    let entry = (|| -> io::Result<Entry> {
+        Ok(Entry {
+            code: fs::read(path("bin"))?,
+            disasm: fs::read_to_string(path("s"))?,
+            relocs: parse_relocs(&fs::read_to_string(path("relocs"))?)?,
+        })
+    })();
+    match entry {
+        Ok(entry) => {
+            debug!("compile cache hit for {}", key);
+            Some(entry)
+        }
+        Err(_) => None,
+    }
+}
+
+/// Store `entry` under `key` in the cache in `dir`. Failing to write the cache is only worth
+/// a warning.
+pub fn store(dir: &Path, key: &str, entry: &Entry) {
+    let relocs = format_relocs(&entry.relocs);
+    // The code goes last: `load` only finds entries whose files are all in place.
+    let result = fs::create_dir_all(dir)
+        .and_then(|()| write_atomic(&dir.join(key).with_extension("s"), entry.disasm.as_bytes()))
+        .and_then(|()| write_atomic(&dir.join(key).with_extension("relocs"), relocs.as_bytes()))
+        .and_then(|()| write_atomic(&dir.join(key).with_extension("bin"), &entry.code));
+    if let Err(e) = result {
+        warn!("failed to write compile cache entry {}: {}", key, e);
+    }
+}
+
+/// Write `contents` to a temporary file and rename it over `path`, so concurrent runners never
+/// see a partial file.
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".tmp{}", std::process::id()));
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+fn format_relocs(relocs: &[Reloc]) -> String {
+    relocs
+        .iter()
+        .map(|r| format!("{}\t{}\t{}\t{}\n", r.offset, r.kind, r.name, r.addend))
+        .collect()
+}
+
+fn parse_relocs(text: &str) -> io::Result<Vec<Reloc>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid relocation");
+    text.lines()
+        .map(|line| {
+            let mut fields = line.splitn(4, '\t');
+            let mut next = || fields.next().ok_or_else(invalid);
+            Ok(Reloc {
+                offset: next()?.parse().map_err(|_| invalid())?,
+                kind: next()?.to_string(),
+                name: next()?.to_string(),
+                addend: next()?.parse().map_err(|_| invalid())?,
+            })
+        })
+        .collect()
+}
+
+/// The backend revision, see the module documentation.
+fn revision() -> String {
+    let mut revision = REVISION.lock().unwrap();
+    revision
+        .get_or_insert_with(|| {
+            if let Ok(revision) = std::env::var(REVISION_ENV) {
+                return revision;
+            }
+            let exe = std::env::current_exe().and_then(|exe| {
+                let meta = fs::metadata(&exe)?;
+                let modified = meta.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+                Ok(format!("{}:{}:{}", exe.display(), meta.len(), modified.as_nanos()))
+            });
+            format!("{}-{}", cranelift_codegen::VERSION, exe.unwrap_or_default())
+        })
+        .clone()
+}
+
+#[test]
+fn relocs_round_trip() {
+    let relocs = vec![Reloc {
+        offset: 12,
+        kind: "RiscvCall".to_string(),
+        name: "%callee".to_string(),
+        addend: -4,
+    }];
+    assert_eq!(parse_relocs(&format_relocs(&relocs)).unwrap(), relocs);
+    assert!(parse_relocs("12\tRiscvCall").is_err());
+}
@@ -1,3 +1,6 @@
+use crate::compile_cache;
+use crate::error::TestError;
+use crate::pretty_anyhow_error;
 use anyhow::Result;
 use core::mem;
 use cranelift_codegen::data_value::DataValue;
@@ -9,8 +12,13 @@ use cranelift_native::builder_with_options;
 use log::trace;
 use memmap2::{Mmap, MmapMut};
 use std::cmp::max;
+use std::any::Any;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::process::Output;
+use std::sync::{Arc, Mutex};
 use std::thread::current;
 use thiserror::Error;
 
@@ -35,16 +43,23 @@ use thiserror::Error;
 /// ```
 pub struct SingleFunctionCompiler {
     isa: Box<dyn TargetIsa>,
-    trampolines: HashMap<Signature, Trampoline>,
+    /// Directory of the [compile_cache], if any.
+    cache: Option<PathBuf>,
 }
 
+/// Identifies the ABI a [Trampoline] was compiled for: the signature it calls and a
+/// description of the ISA and its flags, see [isa_description].
+type TrampolineKey = (Signature, String);
+
+/// Trampolines compiled so far, shared by every compiler in the process.
+static TRAMPOLINES: Mutex<Option<HashMap<TrampolineKey, Arc<Trampoline>>>> = Mutex::new(None);
+
 impl SingleFunctionCompiler {
     /// Build a [SingleFunctionCompiler] from a [TargetIsa]. For functions to be runnable on the
     /// host machine, this [TargetIsa] must match the host machine's ISA (see
-    /// [SingleFunctionCompiler::with_host_isa]).
-    pub fn new(isa: Box<dyn TargetIsa>) -> Self {
-        let trampolines = HashMap::new();
-        Self { isa, trampolines }
+    /// [SingleFunctionCompiler::with_host_isa]). Compiled code is cached in `cache`, if given.
+    pub fn new(isa: Box<dyn TargetIsa>, cache: Option<PathBuf>) -> Self {
+        Self { isa, cache }
     }
 
     /// Compile the passed [Function] to a `CompiledFunction`. This function will:
@@ -52,27 +67,52 @@ impl SingleFunctionCompiler {
     ///  - compile the [Function]
     ///  - compile a `Trampoline` for the [Function]'s signature (or used a cached `Trampoline`;
     ///    this makes it possible to call functions when the signature is not known until runtime.
-    pub fn compile(&mut self, function: Function) -> Result<CompiledFunction, CompilationError> {
+    pub fn compile(&self, function: Function) -> Result<CompiledFunction, CompilationError> {
         let signature = function.signature.clone();
 
         // Compile the function itself.
-        let code_page = compile2(function, self.isa.as_ref())?.0;
+        let code_page = compile2(function, self.isa.as_ref(), self.cache.as_deref())?.0;
 
         // Compile the trampoline to call it, if necessary (it may be cached).
-
-        let trampoline = self
-            .trampolines
-            .entry(signature.clone())
-            .or_insert_with(|| {
-                let ir = make_trampoline(&signature, self.isa.as_ref());
-                let code = compile2(ir, self.isa.as_ref())
-                    .expect("failed to compile trampoline")
-                    .0;
-                Trampoline::new(code)
-            });
+        let trampoline = self.trampoline(&signature);
 
         Ok(CompiledFunction::new(code_page, signature, trampoline))
     }
+
+    /// The trampoline for `signature`, from [TRAMPOLINES] or compiled and added there.
+    fn trampoline(&self, signature: &Signature) -> Arc<Trampoline> {
+        let key = (signature.clone(), isa_description(self.isa.as_ref()));
+        if let Some(trampoline) = TRAMPOLINES
+            .lock()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .get(&key)
+        {
+            return trampoline.clone();
+        }
+
+        // Compile without holding the lock, so other threads aren't held up. If one of them
+        // compiles the same trampoline meanwhile, the first to finish wins.
+        let ir = make_trampoline(signature, self.isa.as_ref());
+        let code = compile2(ir, self.isa.as_ref(), self.cache.as_deref())
+            .expect("failed to compile trampoline")
+            .0;
+        let mut trampolines = TRAMPOLINES.lock().unwrap();
+        trampolines
+            .get_or_insert_with(HashMap::new)
+            .entry(key)
+            .or_insert_with(|| Arc::new(Trampoline::new(code)))
+            .clone()
+    }
+}
+
+/// A description of `isa` and all its settings, which determine the code generated for it.
+pub(crate) fn isa_description(isa: &dyn TargetIsa) -> String {
+    let mut description = format!("{}\n{}", isa.triple(), isa.flags());
+    for flag in isa.isa_flags() {
+        description.push_str(&format!("\n{}", flag));
+    }
+    description
 }
 
 /// Compilation Error when compiling a function.
@@ -85,6 +125,22 @@ pub enum CompilationError {
     /// Cranelift codegen error.
     #[error("Cranelift codegen error")]
     CodegenError(#[from] CodegenError),
+    /// Cranelift codegen error, rendered together with the function that caused it.
+    #[error("{pretty}")]
+    Pretty {
+        /// The kind of codegen error, see [codegen_error_kind].
+        kind: &'static str,
+        /// The error message on its own.
+        message: String,
+        /// The error message along with the function it occurred in.
+        pretty: String,
+    },
+    /// The verifier, run as part of compilation, rejected the function.
+    #[error("{0}")]
+    Verifier(String),
+    /// The code generator panicked.
+    #[error("code generator panicked: {0}")]
+    Panic(String),
     /// Memory mapping error.
     #[error("Memory mapping error")]
     IoError(#[from] std::io::Error),
@@ -128,28 +184,76 @@ impl Trampoline {
 /// let returned = compiled_func.call(&vec![DataValue::I32(2), DataValue::I32(40)]);
 /// assert_eq!(vec![DataValue::I32(42)], returned);
 /// ```
-pub struct CompiledFunction<'a> {
+pub struct CompiledFunction {
     code: Vec<u8>,
     signature: Signature,
-    trampoline: &'a Trampoline,
+    trampoline: Arc<Trampoline>,
+    /// The emulator with the trampoline and code loaded, made by the first call and restored
+    /// before each of the following ones.
+    image: RefCell<Option<LoadedImage>>,
 }
 
 /*
     notice!!! register number begin with 1
 */
-impl<'a> CompiledFunction<'a> {
+impl CompiledFunction {
     /// Build a new [CompiledFunction].
-    pub fn new(page: Vec<u8>, signature: Signature, trampoline: &'a Trampoline) -> Self {
+    pub fn new(page: Vec<u8>, signature: Signature, trampoline: Arc<Trampoline>) -> Self {
         Self {
             code: page,
             signature,
             trampoline,
+            image: RefCell::new(None),
         }
     }
 
-    pub fn call2(&self, arguments: &[DataValue]) -> Vec<DataValue> {
+    /// Run the function in the emulator through its [Trampoline].
+    pub fn call2(&self, arguments: &[DataValue]) -> Result<Vec<DataValue>, TestError> {
+        let mut values = UnboxedValues::make_arguments(arguments, &self.signature);
+        let mut image = self.image.borrow_mut();
+        let result = match &mut *image {
+            Some(image) => image.restore(),
+            None => {
+                *image = Some(self.load(values.0.len()));
+                Ok(())
+            }
+        }
+        .and_then(|()| self.call_loaded(image.as_mut().unwrap(), &mut values));
+        if result.is_err() {
+            // A fault can leave state behind that `restore` doesn't reset (CSRs, privilege
+            // mode, reservations), so start the next call from a fresh image.
+            *image = None;
+        }
+        result?;
+
+        let result = values.collect_returns(&self.signature);
+        trace!("returned {:?}", result);
+        Ok(result)
+    }
+
+    /// Pass `values` to the function loaded in `image`, run it and read the returned values
+    /// back into `values`.
+    fn call_loaded(
+        &self,
+        image: &mut LoadedImage,
+        values: &mut UnboxedValues,
+    ) -> Result<(), TestError> {
+        image.write_arguments(values)?;
+        image.run()?;
+        for (k, slot) in values.0.iter_mut().enumerate().take(self.signature.returns.len()) {
+            let addr = image.arguments_area + (k * UnboxedValues::SLOT_SIZE) as u64;
+            let v1 = image.read(addr)? as u128;
+            let v2 = image.read(addr + 8)? as u128;
+            *slot = v1 | v2 << 64;
+            trace!("return slot {}: {:#x}", k, *slot);
+        }
+        Ok(())
+    }
+
+    /// Load the trampoline and the code into a new emulator, leaving room for `slots` argument
+    /// and return values after them.
+    fn load(&self, slots: usize) -> LoadedImage {
         use rvemu::bus::DRAM_BASE;
-        use rvemu::cpu::DOUBLEWORD;
         use rvemu::emulator::Emulator;
 
         {
@@ -160,41 +264,101 @@ impl<'a> CompiledFunction<'a> {
             file.write_all(&self.trampoline.code[..]).unwrap();
         }
 
-        let mut values = UnboxedValues::make_arguments(arguments, &self.signature);
         let mut dram = Vec::new();
         dram.extend(self.trampoline.data());
-        //
-        let end_at = (dram.len() as u64) + rvemu::bus::DRAM_BASE - 4;
-        let func_addr = (dram.len() as u64) + rvemu::bus::DRAM_BASE;
+        // Stop at the last instruction of the trampoline.
+        let end_at = (dram.len() as u64) + DRAM_BASE - 4;
+        let func_addr = (dram.len() as u64) + DRAM_BASE;
         dram.extend(&self.code[..]);
-        let arguments_area = (dram.len() as u64) + rvemu::bus::DRAM_BASE;
-        let mut args = values.make_vec_u8();
-        dram.extend(&args[..]);
+        let arguments_area = (dram.len() as u64) + DRAM_BASE;
+        dram.resize(dram.len() + slots * UnboxedValues::SLOT_SIZE, 0);
 
         let mut emulator = Emulator::new();
         emulator.initialize_dram(dram);
-
-        // write
         emulator.cpu.xregs.write(10, func_addr);
-
         emulator.cpu.xregs.write(11, arguments_area);
-        emulator.initialize_pc(rvemu::bus::DRAM_BASE);
-        emulator.test_run_end_at(end_at).unwrap();
-        for k in 0..self.signature.returns.len() {
-            let addr = arguments_area + (k as u64) * 16;
-            let v1 = emulator.cpu.bus.read(addr, DOUBLEWORD).unwrap() as u128;
-            let v2 = emulator.cpu.bus.read(addr + 8, DOUBLEWORD).unwrap() as u128;
-            let v: u128 = v1 | v2 << 64;
-            values.0[k] = v;
-            println!("#############{:?}", v);
+
+        let xregs = (0..32).map(|i| emulator.cpu.xregs.read(i)).collect();
+        let fregs = (0..32).map(|i| emulator.cpu.fregs.read(i)).collect();
+        LoadedImage {
+            emulator,
+            end_at,
+            arguments_area,
+            xregs,
+            fregs,
         }
+    }
+}
 
-        let result = values.collect_returns(&self.signature);
-        println!("!!!!!!!!!!!!!!!!!!!!!{:?}", result);
-        return result;
+/// An emulator with a [CompiledFunction] and its [Trampoline] loaded, along with the state to
+/// restore before each call.
+///
+/// Setting up an emulator means allocating and filling its whole DRAM, which dominates the
+/// time of short calls. Instead, the image is loaded once and only what a call can change is
+/// put back: the registers, the argument area and the top of the stack.
+struct LoadedImage {
+    emulator: rvemu::emulator::Emulator,
+    /// Address the trampoline returns to when the call is done.
+    end_at: u64,
+    /// Address of the [UnboxedValues] passed to the trampoline.
+    arguments_area: u64,
+    /// Register values right after loading.
+    xregs: Vec<u64>,
+    fregs: Vec<f64>,
+}
+
+impl LoadedImage {
+    /// Bytes below the initial stack pointer that are cleared between calls. Calls in runtests
+    /// use far less stack than this.
+    const STACK_RESET_SIZE: u64 = 64 * 1024;
+
+    fn restore(&mut self) -> Result<(), TestError> {
+        for (i, v) in self.xregs.iter().enumerate() {
+            self.emulator.cpu.xregs.write(i as u64, *v);
+        }
+        for (i, v) in self.fregs.iter().enumerate() {
+            self.emulator.cpu.fregs.write(i as u64, *v);
+        }
+        let sp = self.xregs[2];
+        for addr in (sp - Self::STACK_RESET_SIZE..sp).step_by(8) {
+            self.write(addr, 0)?;
+        }
+        Ok(())
+    }
+
+    fn write_arguments(&mut self, values: &UnboxedValues) -> Result<(), TestError> {
+        for (k, slot) in values.0.iter().enumerate() {
+            let addr = self.arguments_area + (k * UnboxedValues::SLOT_SIZE) as u64;
+            self.write(addr, *slot as u64)?;
+            self.write(addr + 8, (*slot >> 64) as u64)?;
+        }
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), TestError> {
+        use rvemu::bus::DRAM_BASE;
+        let emulator = &mut self.emulator;
+        emulator.initialize_pc(DRAM_BASE);
+        emulator
+            .test_run_end_at(self.end_at)
+            .map_err(|e| TestError::Emulator(format!("{:?} at pc {:#x}", e, emulator.cpu.pc)))
+    }
+
+    fn read(&mut self, addr: u64) -> Result<u64, TestError> {
+        use rvemu::cpu::DOUBLEWORD;
+        self.emulator.cpu.bus.read(addr, DOUBLEWORD).map_err(|e| {
+            TestError::Emulator(format!("reading result at {:#x}: {:?}", addr, e))
+        })
     }
 
-    //
+    fn write(&mut self, addr: u64, value: u64) -> Result<(), TestError> {
+        use rvemu::cpu::DOUBLEWORD;
+        self.emulator
+            .cpu
+            .bus
+            .write(addr, value, DOUBLEWORD)
+            .map_err(|e| TestError::Emulator(format!("writing at {:#x}: {:?}", addr, e)))
+    }
 }
 
 /// A container for laying out the [ValueData]s in memory in a way that the [Trampoline] can
@@ -261,20 +425,86 @@ impl UnboxedValues {
 //     Ok(Vec::from_iter(code_info.buffer.data().iter().map(|v| *v)))
 // }
 
-fn compile2(
+/// Compile a [Function], returning its machine code and disassembly. Successful compilations
+/// are looked up in and added to the [compile_cache] in `cache`, if given.
+pub(crate) fn compile2(
     function: Function,
     isa: &dyn TargetIsa,
+    cache: Option<&Path>,
 ) -> Result<(Vec<u8>, String), CompilationError> {
+    let cache = cache.map(|dir| (dir, compile_cache::key(&function, isa)));
+    if let Some((dir, key)) = &cache {
+        if let Some(entry) = compile_cache::load(dir, key) {
+            return Ok((entry.code, entry.disasm));
+        }
+    }
+
     // Compile and encode the result to machine code.
     use cranelift_codegen::Context;
     let mut c = Context::for_function(function);
     c.want_disasm = true;
-    let result = c.compile(isa).unwrap();
+    // A panic in the backend shouldn't take the whole runner down with it.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        c.compile(isa).map(|result| compile_cache::Entry {
+            code: Vec::from_iter(result.buffer.data().iter().map(|v| *v)),
+            disasm: result.disasm.clone().unwrap(),
+            relocs: result
+                .buffer
+                .relocs()
+                .iter()
+                .map(|r| compile_cache::Reloc {
+                    offset: r.offset,
+                    kind: r.kind.to_string(),
+                    name: r.name.to_string(),
+                    addend: r.addend,
+                })
+                .collect(),
+        })
+    }))
+    .map_err(|payload| CompilationError::Panic(panic_message(payload.as_ref())))?;
+
+    let entry = result.map_err(|err| {
+        let kind = codegen_error_kind(&err);
+        let message = err.to_string();
+        let pretty = pretty_anyhow_error(&c.func, err).to_string();
+        if kind == "verifier" {
+            CompilationError::Verifier(pretty)
+        } else {
+            CompilationError::Pretty {
+                kind,
+                message,
+                pretty,
+            }
+        }
+    })?;
+    if let Some((dir, key)) = &cache {
+        compile_cache::store(dir, key, &entry);
+    }
+    Ok((entry.code, entry.disasm))
+}
 
-    Ok((
-        Vec::from_iter(result.buffer.data().iter().map(|v| *v)),
-        result.disasm.clone().unwrap(),
-    ))
+/// A short name for the kind of `err`, as used by `; compile-error:` test directives.
+pub fn codegen_error_kind(err: &CodegenError) -> &'static str {
+    match err {
+        CodegenError::Verifier(_) => "verifier",
+        CodegenError::ImplLimitExceeded => "impl-limit-exceeded",
+        CodegenError::CodeTooLarge => "code-too-large",
+        CodegenError::Unsupported(_) => "unsupported",
+        CodegenError::Regalloc(_) => "regalloc",
+        #[allow(unreachable_patterns)]
+        _ => "other",
+    }
+}
+
+/// Extract the message of a caught panic.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
 }
 
 /// Build the Cranelift IR for moving the memory-allocated [DataValue]s to their correct location
@@ -0,0 +1,187 @@
+//! Project configuration file.
+//!
+//! `run_all` reads a `run_ci.toml` file found in the working directory or one of its parents,
+//! or the file given with `--config`, and passes what `run_one` needs on through its command
+//! line and environment. Relative paths in the file are resolved against the directory containing it.
+//!
+//! ```toml
+//! wasmtime = "../wasmtime"
+//! suites = ["cranelift/filetests/filetests/isa/riscv64"]
+//! exclude = ["elf-gd"]
+//! flags = [["opt_level=none"], ["opt_level=speed"]]
+//!
+//! [limits]
+//! timeout_secs = 60
+//! ```
+
+use anyhow::{bail, Context as _};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// File name looked for when no `--config` flag is given.
+pub const CONFIG_FILE_NAME: &str = "run_ci.toml";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Location of the wasmtime checkout.
+    pub wasmtime: PathBuf,
+    /// Directories searched recursively for `.clif` files, relative to the wasmtime checkout.
+    pub suites: Vec<PathBuf>,
+    /// If not empty, only test files whose path contains one of these strings are run.
+    pub include: Vec<String>,
+    /// Test files whose path contains one of these strings are not run.
+    pub exclude: Vec<String>,
+    /// Every test file is run once per entry, each a list of `name=value` Cranelift settings.
+    pub flags: Vec<Vec<String>>,
+    /// The emulator that runs the compiled code, passed to `run_one` with `--emulator`.
+    pub emulator: Emulator,
+    /// The `run_one` binary `run_all` invokes for each test file.
+    pub runner: PathBuf,
+    /// Stop at the first failing test file.
+    pub fail_fast: bool,
+    /// Directory `run_one` caches compiled functions in. No caching if unset.
+    pub compile_cache: Option<PathBuf>,
+    pub limits: Limits,
+}
+
+/// The emulators functions can be run on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Emulator {
+    Rvemu,
+}
+
+impl Emulator {
+    /// Parse the value of `--emulator`, spelled as in the configuration file.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "rvemu" => Some(Emulator::Rvemu),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Emulator::Rvemu => "rvemu",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Wall-clock limit for one test file, after which `run_one` is killed.
+    pub timeout_secs: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            wasmtime: PathBuf::from("../wasmtime"),
+            suites: vec![PathBuf::from("cranelift/filetests/filetests/runtests")],
+            include: Vec::new(),
+            exclude: Vec::new(),
+            flags: vec![Vec::new()],
+            emulator: Emulator::Rvemu,
+            runner: PathBuf::from("./target/debug/run_one"),
+            fail_fast: false,
+            compile_cache: None,
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Load the configuration from `path` if given, otherwise from the nearest
+    /// [CONFIG_FILE_NAME], falling back to the defaults when there is none.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match Self::discover()? {
+                Some(path) => path,
+                None => return Ok(Self::default()),
+            },
+        };
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        Self::parse(&text, base).with_context(|| format!("invalid config {}", path.display()))
+    }
+
+    /// Parse a configuration, resolving relative paths against `base`.
+    pub fn parse(text: &str, base: &Path) -> anyhow::Result<Self> {
+        let mut config: Config = toml::from_str(text)?;
+        if config.flags.is_empty() {
+            bail!("`flags` needs at least one entry; use `[[]]` for the default settings");
+        }
+        config.wasmtime = base.join(&config.wasmtime);
+        config.runner = base.join(&config.runner);
+        config.compile_cache = config.compile_cache.map(|dir| base.join(dir));
+        Ok(config)
+    }
+
+    /// Look for [CONFIG_FILE_NAME] in the working directory and its parents.
+    fn discover() -> anyhow::Result<Option<PathBuf>> {
+        let cwd = std::env::current_dir()?;
+        Ok(cwd
+            .ancestors()
+            .map(|dir| dir.join(CONFIG_FILE_NAME))
+            .find(|path| path.is_file()))
+    }
+
+    /// The suite directories, resolved against the wasmtime checkout.
+    pub fn suite_dirs(&self) -> Vec<PathBuf> {
+        self.suites.iter().map(|s| self.wasmtime.join(s)).collect()
+    }
+
+    /// Should the test file at `path` be run?
+    pub fn selects(&self, path: &Path) -> bool {
+        let path = path.to_string_lossy();
+        if !path.ends_with(".clif") {
+            return false;
+        }
+        if !self.include.is_empty() && !self.include.iter().any(|p| path.contains(p.as_str())) {
+            return false;
+        }
+        !self.exclude.iter().any(|p| path.contains(p.as_str()))
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.limits.timeout_secs.map(Duration::from_secs)
+    }
+}
+
+#[test]
+fn parse_config() {
+    let config = Config::parse(
+        r#"
+        wasmtime = "../wasmtime"
+        suites = ["cranelift/filetests/filetests/isa/riscv64"]
+        exclude = ["elf-gd"]
+        flags = [["opt_level=none"], ["opt_level=speed"]]
+        emulator = "rvemu"
+        compile_cache = "target/compile-cache"
+
+        [limits]
+        timeout_secs = 30
+        "#,
+        Path::new("/ci"),
+    )
+    .unwrap();
+    assert_eq!(config.wasmtime, Path::new("/ci/../wasmtime"));
+    assert_eq!(config.emulator, Emulator::Rvemu);
+    assert_eq!(config.flags.len(), 2);
+    assert_eq!(
+        config.compile_cache.as_deref(),
+        Some(Path::new("/ci/target/compile-cache"))
+    );
+    assert_eq!(config.timeout(), Some(Duration::from_secs(30)));
+    assert!(config.selects(Path::new("isa/riscv64/condbr.clif")));
+    assert!(!config.selects(Path::new("isa/riscv64/elf-gd.clif")));
+    assert!(!config.selects(Path::new("isa/riscv64/README.md")));
+
+    assert!(Config::parse("flags = []", Path::new(".")).is_err());
+    assert!(Config::parse("emulator = \"qemu\"", Path::new(".")).is_err());
+}
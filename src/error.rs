@@ -0,0 +1,191 @@
+//! Classified test failures.
+//!
+//! Subtests return `anyhow` errors; the ones we can classify carry a [TestError] somewhere in
+//! their chain. `runone` wraps each failure in a [Diagnostic] with the file and line it belongs
+//! to, which prints as `file:line: kind: message` and picks the process exit code.
+
+use crate::compiler::CompilationError;
+use crate::exit_code;
+use crate::Context;
+use std::fmt;
+use thiserror::Error;
+
+/// The categories of test failure the runner can tell apart.
+#[derive(Error, Debug)]
+pub enum TestError {
+    /// The test file, or a command inside it, could not be parsed.
+    #[error("{message}")]
+    Parse { line: usize, message: String },
+    /// The IR verifier rejected the function.
+    #[error("{0}")]
+    Verifier(String),
+    /// Compiling the function failed.
+    #[error(transparent)]
+    Compilation(#[from] CompilationError),
+    /// The function produced a result other than the expected one.
+    #[error("{0}")]
+    Mismatch(String),
+    /// The emulator faulted while running the function.
+    #[error("{0}")]
+    Emulator(String),
+    /// The function trapped where no trap was expected.
+    #[error("{0}")]
+    Trap(String),
+    /// The interpreter stopped the function after the given number of steps.
+    #[error("interpreter ran out of fuel after {0} steps")]
+    OutOfFuel(u64),
+    /// The test can't be run by this runner. Only a failure in strict mode.
+    #[error("skipped: {0}")]
+    Skipped(String),
+}
+
+impl TestError {
+    /// The kind printed in diagnostics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TestError::Parse { .. } => "parse error",
+            TestError::Verifier(_) => "verifier error",
+            TestError::Compilation(CompilationError::Panic(_)) => "codegen panic",
+            TestError::Compilation(CompilationError::Verifier(_)) => "verifier error",
+            TestError::Compilation(_) => "codegen error",
+            TestError::Mismatch(_) => "result mismatch",
+            TestError::Emulator(_) => "emulator fault",
+            TestError::Trap(_) => "unexpected trap",
+            TestError::OutOfFuel(_) => "out of fuel",
+            TestError::Skipped(_) => "skipped",
+        }
+    }
+
+    /// The process exit code for this kind of failure.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            TestError::Parse { .. } => exit_code::PARSE,
+            TestError::Verifier(_) => exit_code::VERIFIER,
+            TestError::Compilation(CompilationError::Panic(_)) => exit_code::CODEGEN_PANIC,
+            TestError::Compilation(CompilationError::Verifier(_)) => exit_code::VERIFIER,
+            TestError::Compilation(_) => exit_code::CODEGEN,
+            TestError::Mismatch(_) => exit_code::MISMATCH,
+            TestError::Emulator(_) => exit_code::EMULATOR,
+            TestError::Trap(_) => exit_code::TRAP,
+            TestError::OutOfFuel(_) => exit_code::OUT_OF_FUEL,
+            TestError::Skipped(_) => exit_code::SKIPPED,
+        }
+    }
+}
+
+/// A failure located in a test file.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub error: anyhow::Error,
+    /// Log output captured while the failing function ran, see [crate::logging::capture].
+    pub log: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(file: &str, line: usize, error: anyhow::Error) -> Self {
+        // Parse errors know better than the caller which line they are about.
+        let line = match error.chain().find_map(|e| e.downcast_ref::<TestError>()) {
+            Some(TestError::Parse { line, .. }) => *line,
+            _ => line,
+        };
+        Self {
+            file: file.to_string(),
+            line,
+            error,
+            log: None,
+        }
+    }
+
+    /// The classified error in the chain, if any.
+    pub fn test_error(&self) -> Option<&TestError> {
+        self.error.chain().find_map(|e| e.downcast_ref::<TestError>())
+    }
+
+    pub fn kind(&self) -> &'static str {
+        self.test_error().map_or("error", TestError::kind)
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.test_error()
+            .map_or(exit_code::OTHER, TestError::exit_code)
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}: {:#}", self.file, self.line, self.kind(), self.error)?;
+        if let Some(log) = &self.log {
+            write!(f, "\nlog output:\n{}", log)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+/// All failures of one subtest run on one function, e.g. one per failing run command.
+///
+/// Subtests that can keep going after a failure collect them here and return the whole set
+/// as their error, which `runone` unpacks into the file summary.
+#[derive(Debug, Default)]
+pub struct Failures(pub Vec<Diagnostic>);
+
+impl Failures {
+    /// Record `error`, which happened on line `line` of the test file in `context`.
+    pub fn push(&mut self, context: &Context, line: usize, error: impl Into<anyhow::Error>) {
+        self.0
+            .push(Diagnostic::new(context.file_path, line, error.into()));
+    }
+
+    pub fn into_result(self) -> anyhow::Result<()> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(self.into())
+        }
+    }
+}
+
+impl fmt::Display for Failures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, d) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", d)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Failures {}
+
+/// The exit code for an error returned by `runone::run`.
+pub fn exit_code_of(err: &anyhow::Error) -> i32 {
+    match err.downcast_ref::<Diagnostic>() {
+        Some(d) => d.exit_code(),
+        None => err
+            .chain()
+            .find_map(|e| e.downcast_ref::<TestError>())
+            .map_or(exit_code::OTHER, TestError::exit_code),
+    }
+}
+
+#[test]
+fn diagnostic_format_and_exit_code() {
+    use anyhow::Context as _;
+    let err = anyhow::Error::from(TestError::Mismatch("Failed test: run".to_string()))
+        .context("run");
+    let d = Diagnostic::new("a.clif", 12, err);
+    assert_eq!(d.to_string(), "a.clif:12: result mismatch: run: Failed test: run");
+    assert_eq!(exit_code_of(&d.into()), exit_code::MISMATCH);
+
+    let parse = TestError::Parse {
+        line: 3,
+        message: "expected type".to_string(),
+    };
+    let d = Diagnostic::new("a.clif", 1, parse.into());
+    assert_eq!(d.to_string(), "a.clif:3: parse error: expected type");
+}
@@ -0,0 +1,49 @@
+//! Process exit codes used by `run_one`, so that `run_all` can tell failure kinds apart.
+//!
+//! This module has no dependencies so both binaries can include it.
+
+/// The test file passed.
+pub const SUCCESS: i32 = 0;
+/// A failure that doesn't fit any of the categories below (I/O, bad arguments, ...).
+pub const OTHER: i32 = 1;
+/// The test file, or a command inside it, could not be parsed.
+pub const PARSE: i32 = 2;
+/// The IR verifier rejected a function.
+pub const VERIFIER: i32 = 3;
+/// The code generator returned an error.
+pub const CODEGEN: i32 = 4;
+/// The code generator panicked.
+pub const CODEGEN_PANIC: i32 = 5;
+/// A function ran but produced the wrong result or disassembly.
+pub const MISMATCH: i32 = 6;
+/// The emulator faulted while running a function.
+pub const EMULATOR: i32 = 7;
+/// `run_all` killed `run_one` after the configured timeout. Never returned by `run_one` itself.
+pub const TIMEOUT: i32 = 8;
+/// A subtest was skipped while running in strict mode.
+pub const SKIPPED: i32 = 9;
+/// A function trapped where no trap was expected.
+pub const TRAP: i32 = 10;
+/// The interpreter ran out of fuel, likely in an infinite loop.
+pub const OUT_OF_FUEL: i32 = 11;
+/// Exit code of a Rust panic that escaped the runner.
+pub const PANIC: i32 = 101;
+
+/// A short description of an exit code returned by `run_one`.
+pub fn describe(code: i32) -> &'static str {
+    match code {
+        SUCCESS => "ok",
+        PARSE => "parse error",
+        VERIFIER => "verifier error",
+        CODEGEN => "codegen error",
+        CODEGEN_PANIC => "codegen panic",
+        MISMATCH => "result mismatch",
+        EMULATOR => "emulator fault",
+        TIMEOUT => "timeout",
+        SKIPPED => "skipped (strict)",
+        TRAP => "unexpected trap",
+        OUT_OF_FUEL => "out of fuel",
+        PANIC => "runner panic",
+        _ => "error",
+    }
+}
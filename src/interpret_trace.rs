@@ -0,0 +1,218 @@
+//! IR-level execution traces for `test interpret`.
+//!
+//! With tracing on, the interpreter is driven one instruction at a time and every executed
+//! instruction is written out along with the values it read and defined:
+//!
+//! ```text
+//! ; %add(1, 2)
+//! block0:
+//!     v2 = iadd v0, v1  ; v0 = 1, v1 = 2 -> v2 = 3
+//!     return v2  ; v2 = 3 -> return [3]
+//! ```
+//!
+//! Each run command gets its own file in the working directory, named by [trace_path] after the
+//! test file, the called function and the line of the command, so the traces of all failing
+//! calls are still there after a whole suite has run. Running the same file again overwrites
+//! them.
+//!
+//! The emulator side has no matching instruction trace yet: all it leaves behind are the
+//! `code.bin` and `trampoline.bin` dumps of the last function it loaded, which can be
+//! disassembled to compare against the IR trace by hand.
+
+use cranelift_codegen::data_value::DataValue;
+use cranelift_codegen::ir::{Block, Function, Inst, Value};
+use cranelift_interpreter::instruction::DfgInstructionContext;
+use cranelift_interpreter::interpreter::{InterpreterError, InterpreterState};
+use cranelift_interpreter::state::State;
+use cranelift_interpreter::step::{step, ControlFlow};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// Environment variable turning tracing on for every `test interpret`, without the `trace`
+/// option in the test file.
+pub const TRACE_ENV: &str = "CRANELIFT_INTERPRET_TRACE";
+
+/// The file the trace of the call to `%func` on line `line` of the test file `file` goes to.
+pub fn trace_path(file: &str, func: &str, line: usize) -> PathBuf {
+    // Tests read from stdin are named `<stdin>`, which makes a poor file name.
+    let stem = Path::new(file)
+        .file_stem()
+        .map_or("stdin".into(), |stem| stem.to_string_lossy())
+        .replace(|c: char| c == '<' || c == '>', "");
+    PathBuf::from(format!("{}.interpret.{}.{}.trace", stem, func, line))
+}
+
+/// Runs functions like [cranelift_interpreter::interpreter::Interpreter], recording a trace.
+pub struct Tracer<'a> {
+    state: InterpreterState<'a>,
+    fuel: Option<u64>,
+    depth: usize,
+    trace: String,
+}
+
+impl<'a> Tracer<'a> {
+    pub fn new(state: InterpreterState<'a>, fuel: Option<u64>) -> Self {
+        Self {
+            state,
+            fuel,
+            depth: 0,
+            trace: String::new(),
+        }
+    }
+
+    /// Call `function` with `arguments`, tracing every instruction it and its callees execute.
+    pub fn call(
+        &mut self,
+        function: &'a Function,
+        arguments: &[DataValue],
+    ) -> Result<ControlFlow<'a, DataValue>, InterpreterError> {
+        let entry = function
+            .layout
+            .entry_block()
+            .expect("to have a first block");
+        self.state.push_frame(function);
+        let params = function.dfg.block_params(entry);
+        self.assign(params, arguments);
+        self.block(function, entry)
+    }
+
+    /// Write the trace so far to `path`, headed by `header`.
+    pub fn write(&self, path: &Path, header: &str) -> std::io::Result<()> {
+        std::fs::write(path, format!("; {}\n{}", header, self.trace))
+    }
+
+    fn block(
+        &mut self,
+        function: &'a Function,
+        mut block: Block,
+    ) -> Result<ControlFlow<'a, DataValue>, InterpreterError> {
+        let layout = &function.layout;
+        // Branches jump back here rather than recursing, so long loops don't overflow the stack.
+        'blocks: loop {
+            self.line(format_args!("{}:", block));
+            let mut maybe_inst = layout.first_inst(block);
+            while let Some(inst) = maybe_inst {
+                if let Some(fuel) = &mut self.fuel {
+                    if *fuel == 0 {
+                        return Err(InterpreterError::FuelExhausted);
+                    }
+                    *fuel -= 1;
+                }
+
+                let inputs = self.values(function.dfg.inst_args(inst));
+                let inst_context = DfgInstructionContext::new(inst, &function.dfg);
+                let flow = step(&mut self.state, inst_context)?;
+                match flow {
+                    ControlFlow::Assign(values) => {
+                        let results = function.dfg.inst_results(inst);
+                        self.assign(results, &values);
+                        let outputs = format_values(results, &values);
+                        self.inst(function, inst, &inputs, &outputs);
+                        maybe_inst = layout.next_inst(inst);
+                    }
+                    ControlFlow::Continue => {
+                        self.inst(function, inst, &inputs, "");
+                        maybe_inst = layout.next_inst(inst);
+                    }
+                    ControlFlow::ContinueAt(next, block_arguments) => {
+                        let params = function.dfg.block_params(next);
+                        self.assign(params, &block_arguments);
+                        let outputs =
+                            format!("{}({})", next, format_values(params, &block_arguments));
+                        self.inst(function, inst, &inputs, &outputs);
+                        block = next;
+                        continue 'blocks;
+                    }
+                    ControlFlow::Call(called_function, arguments) => {
+                        let outputs = format!("call {}", called_function.name);
+                        self.inst(function, inst, &inputs, &outputs);
+                        self.depth += 1;
+                        let returned = self.call(called_function, &arguments)?;
+                        self.depth -= 1;
+                        let values = match returned {
+                            ControlFlow::Return(values) => values,
+                            trap => return Ok(trap),
+                        };
+                        let results = function.dfg.inst_results(inst);
+                        self.assign(results, &values);
+                        self.line(format_args!("  -> {}", format_values(results, &values)));
+                        maybe_inst = layout.next_inst(inst);
+                    }
+                    ControlFlow::Return(values) => {
+                        let outputs = format!("return [{}]", join(&values));
+                        self.inst(function, inst, &inputs, &outputs);
+                        self.state.pop_frame();
+                        return Ok(ControlFlow::Return(values));
+                    }
+                    ControlFlow::Trap(trap) => {
+                        self.inst(function, inst, &inputs, &format!("trap {:?}", trap));
+                        return Ok(ControlFlow::Trap(trap));
+                    }
+                }
+            }
+            return Err(InterpreterError::Unreachable);
+        }
+    }
+
+    fn assign(&mut self, names: &[Value], values: &[DataValue]) {
+        for (&name, value) in names.iter().zip(values) {
+            self.state.set_value(name, value.clone());
+        }
+    }
+
+    /// The current values of `names`, formatted as `v0 = 1, v1 = 2`.
+    fn values(&self, names: &[Value]) -> String {
+        let values: Vec<_> = names
+            .iter()
+            .map(|&name| match self.state.get_value(name) {
+                Some(value) => format!("{} = {}", name, value),
+                None => format!("{} = ?", name),
+            })
+            .collect();
+        values.join(", ")
+    }
+
+    fn inst(&mut self, function: &Function, inst: Inst, inputs: &str, outputs: &str) {
+        let inst = function.dfg.display_inst(inst);
+        match (inputs.is_empty(), outputs.is_empty()) {
+            (true, true) => self.line(format_args!("    {}", inst)),
+            (false, true) => self.line(format_args!("    {}  ; {}", inst, inputs)),
+            (true, false) => self.line(format_args!("    {}  ; -> {}", inst, outputs)),
+            (false, false) => {
+                self.line(format_args!("    {}  ; {} -> {}", inst, inputs, outputs))
+            }
+        }
+    }
+
+    /// Append a line, indented by the call depth.
+    fn line(&mut self, args: std::fmt::Arguments) {
+        let indent = self.depth * 4;
+        writeln!(self.trace, "{:indent$}{}", "", args, indent = indent).unwrap();
+    }
+}
+
+fn format_values(names: &[Value], values: &[DataValue]) -> String {
+    let pairs: Vec<_> = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| format!("{} = {}", name, value))
+        .collect();
+    pairs.join(", ")
+}
+
+fn join(values: &[DataValue]) -> String {
+    let values: Vec<_> = values.iter().map(|v| v.to_string()).collect();
+    values.join(", ")
+}
+
+#[test]
+fn trace_path_names_the_test_file() {
+    assert_eq!(
+        trace_path("runtests/iadd.clif", "add", 12),
+        Path::new("iadd.interpret.add.12.trace")
+    );
+    assert_eq!(
+        trace_path("<stdin>", "add", 12),
+        Path::new("stdin.interpret.add.12.trace")
+    );
+}
@@ -1,25 +1,82 @@
 //! Test command for interpreting CLIF files and verifying their results
 //!
 //! The `interpret` test command interprets each function on the host machine
-//! using [RunCommand](cranelift_reader::RunCommand)s.
+//! using [RunCommand](cranelift_reader::RunCommand)s. Besides `; run:` and `; print:`, it
+//! accepts `; trap:` commands for calls that are expected to trap, see [TrapCommand]. `test run`
+//! checks those too.
 
+use crate::error::{Failures, TestError};
+use crate::interpret_trace::{self, Tracer};
+use crate::run_bless::{check_run_commands, parse_run_commands, BlessSource};
+use crate::runone::Options;
+use crate::runtest_environment::RuntestEnvironment;
+use crate::sub_test::match_directive;
 use crate::{Context, SubTest};
+use cranelift_codegen::data_value::DataValue;
+use cranelift_codegen::ir::types::I64;
+use cranelift_codegen::ir::{Signature, TrapCode};
 use cranelift_codegen::{self, ir};
 use cranelift_interpreter::environment::FunctionStore;
-use cranelift_interpreter::interpreter::{Interpreter, InterpreterState};
-use cranelift_interpreter::step::ControlFlow;
-use cranelift_reader::{parse_run_command, TestCommand};
+use cranelift_interpreter::interpreter::{
+    HeapInit, Interpreter, InterpreterError, InterpreterState,
+};
+use cranelift_interpreter::step::{ControlFlow, CraneliftTrap};
+use cranelift_reader::{parse_run_command, Invocation, RunCommand, TestCommand, TestOption};
 use log::trace;
 use std::borrow::Cow;
+use std::fmt;
+use std::path::PathBuf;
 
-struct TestInterpret;
+/// Environment variable with the interpreter fuel when no `--interpreter-fuel` flag is given.
+pub const FUEL_ENV: &str = "CRANELIFT_INTERPRETER_FUEL";
+
+/// Instructions a single run command may execute. Far more than any runtest needs, but it turns
+/// an infinite loop into a failure instead of a hang.
+pub const DEFAULT_FUEL: u64 = 10_000_000;
+
+/// The fuel limit set by `options`, `None` if unlimited.
+fn fuel(options: &Options) -> Option<u64> {
+    match options.interpreter_fuel {
+        0 => None,
+        fuel => Some(fuel),
+    }
+}
+
+struct TestInterpret {
+    /// Write an IR-level execution trace of every run command, see [crate::interpret_trace].
+    trace: bool,
+}
 
 pub fn subtest(parsed: &TestCommand) -> anyhow::Result<Box<dyn SubTest>> {
     assert_eq!(parsed.command, "interpret");
-    if !parsed.options.is_empty() {
-        anyhow::bail!("No options allowed on {}", parsed);
+    let mut test = TestInterpret { trace: false };
+    for option in parsed.options.iter() {
+        match option {
+            TestOption::Flag("trace") => test.trace = true,
+            _ => anyhow::bail!("unknown option on {}", parsed),
+        }
+    }
+    Ok(Box::new(test))
+}
+
+impl TestInterpret {
+    /// Where to trace `invocation`, run by the command on line `line`, if tracing is on.
+    fn trace_path(
+        &self,
+        context: &Context,
+        invocation: &Invocation,
+        line: usize,
+    ) -> Option<PathBuf> {
+        if self.trace || context.options.interpreter_trace {
+            Some(interpret_trace::trace_path(
+                context.file_path,
+                &invocation.func,
+                line,
+            ))
+        } else {
+            None
+        }
     }
-    Ok(Box::new(TestInterpret))
 }
 
 impl SubTest for TestInterpret {
@@ -36,29 +93,264 @@ impl SubTest for TestInterpret {
     }
 
     fn run(&self, func: Cow<ir::Function>, context: &Context) -> anyhow::Result<()> {
-        for comment in context.details.comments.iter() {
-            if let Some(command) = parse_run_command(comment.text, &func.signature)? {
-                trace!("Parsed run command: {}", command);
-
-                let mut env = FunctionStore::default();
-                env.add(func.name.to_string(), &func);
-
-                command
-                    .run(|func_name, args| {
-                        // Because we have stored function names with a leading %, we need to re-add it.
-                        let func_name = &format!("%{}", func_name);
-                        let state = InterpreterState::default().with_function_store(env);
-                        match Interpreter::new(state).call_by_name(func_name, args) {
-                            Ok(ControlFlow::Return(results)) => Ok(results.to_vec()),
-                            Ok(_) => {
-                                panic!("Unexpected returned control flow--this is likely a bug.")
-                            }
-                            Err(t) => Err(format!("unexpected trap: {:?}", t)),
-                        }
-                    })
-                    .map_err(|e| anyhow::anyhow!("{}", e))?;
+        let test_env = RuntestEnvironment::parse(&context.details.comments[..])?;
+        test_env.validate_signature(&func)?;
+
+        // Register every function in the file so calls between them resolve. `add` refuses a
+        // name twice, so the function under test takes the place of its copy in the file.
+        let mut env = FunctionStore::default();
+        for other in context.functions.iter().filter(|f| f.name != func.name) {
+            env.add(other.name.to_string(), other);
+        }
+        env.add(func.name.to_string(), &func);
+
+        let fuel = fuel(context.options);
+        let mut failures = Failures::default();
+        for (line, trap) in parse_trap_commands(context, &func.signature, &mut failures) {
+            let trace = self.trace_path(context, &trap.invocation, line);
+            let result = interpret(&env, &test_env, &trap.invocation, fuel, trace)
+                .and_then(|outcome| trap.check(&outcome).map_err(Into::into));
+            if let Err(e) = result {
+                failures.push(context, line, e);
             }
         }
-        Ok(())
+
+        let commands = parse_run_commands(context, &func.signature, &mut failures);
+        check_run_commands(
+            context,
+            commands,
+            BlessSource::Interpreter,
+            failures,
+            |invocation, line| {
+                let trace = self.trace_path(context, invocation, line);
+                match interpret(&env, &test_env, invocation, fuel, trace)? {
+                    Outcome::Return(actual) => Ok(actual),
+                    outcome => Err(TestError::Trap(format!("unexpected {}", outcome)).into()),
+                }
+            },
+        )
+    }
+}
+
+/// How an interpreted call ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// The function returned these values.
+    Return(Vec<DataValue>),
+    /// The function trapped. The code is `None` for traps that don't carry one, such as
+    /// resumable traps.
+    Trap(Option<TrapCode>),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Return(values) => {
+                let values: Vec<_> = values.iter().map(|v| v.to_string()).collect();
+                write!(f, "return [{}]", values.join(", "))
+            }
+            Outcome::Trap(Some(code)) => write!(f, "trap: {}", code),
+            Outcome::Trap(None) => write!(f, "trap"),
+        }
+    }
+}
+
+/// Interpret `invocation` with the functions in `env`, passing it the vmctx described by
+/// `test_env` if there is one, with at most `fuel` instructions. With `trace`, the execution is
+/// written there by a [Tracer].
+fn interpret(
+    env: &FunctionStore,
+    test_env: &RuntestEnvironment,
+    invocation: &Invocation,
+    fuel: Option<u64>,
+    trace: Option<PathBuf>,
+) -> anyhow::Result<Outcome> {
+    // Because we have stored function names with a leading %, we need to re-add it.
+    let func_name = &format!("%{}", invocation.func);
+    let mut state = InterpreterState::default().with_function_store(env.clone());
+
+    let mut args = Vec::with_capacity(invocation.args.len() + 1);
+    if test_env.is_active() {
+        args.push(register_heaps(&mut state, test_env));
+    }
+    args.extend_from_slice(&invocation.args);
+
+    let result = if let Some(path) = trace {
+        let mut tracer = Tracer::new(state, fuel);
+        let result = match env.get_by_name(func_name) {
+            Some(function) => tracer.call(function, &args),
+            None => Err(InterpreterError::UnknownFunctionName(func_name.to_string())),
+        };
+        tracer.write(&path, &invocation.to_string())?;
+        result
+    } else {
+        Interpreter::new(state).with_fuel(fuel).call_by_name(func_name, &args)
+    };
+    match result {
+        Ok(ControlFlow::Return(results)) => Ok(Outcome::Return(results.to_vec())),
+        Ok(ControlFlow::Trap(CraneliftTrap::User(code))) => Ok(Outcome::Trap(Some(code))),
+        Ok(ControlFlow::Trap(_)) => Ok(Outcome::Trap(None)),
+        Ok(flow) => anyhow::bail!("unexpected control flow from the interpreter: {:?}", flow),
+        Err(InterpreterError::FuelExhausted) => Err(TestError::OutOfFuel(fuel.unwrap()).into()),
+        Err(e) => anyhow::bail!("interpreter error: {}", e),
     }
 }
+
+/// A `; trap: %f(args)` or `; trap: %f(args) == <trap code>` command: the call is expected to
+/// trap, with the given trap code if there is one.
+pub struct TrapCommand {
+    pub invocation: Invocation,
+    pub code: Option<TrapCode>,
+}
+
+impl TrapCommand {
+    /// Parse a trap command from a comment, returning `None` if it isn't one.
+    pub fn parse(comment: &str, signature: &Signature) -> Result<Option<Self>, String> {
+        let tail = match match_directive(comment, "trap:") {
+            Some(tail) => tail,
+            None => return Ok(None),
+        };
+        let (call, code) = match tail.rsplit_once("==") {
+            Some((call, code)) => {
+                let code = code.trim();
+                let code = code
+                    .parse::<TrapCode>()
+                    .map_err(|_| format!("unknown trap code `{}`", code))?;
+                (call.trim(), Some(code))
+            }
+            None => (tail, None),
+        };
+        // Reuse the run command parser for the invocation, so arguments are typed the same way.
+        let invocation = match parse_run_command(&format!("print: {}", call), signature) {
+            Ok(Some(RunCommand::Print(invocation))) => invocation,
+            Ok(_) => return Err(format!("expected an invocation, got `{}`", call)),
+            Err(e) => return Err(e.message),
+        };
+        Ok(Some(TrapCommand { invocation, code }))
+    }
+
+    /// Check that `outcome` is the expected trap.
+    pub fn check(&self, outcome: &Outcome) -> Result<(), TestError> {
+        match (outcome, self.code) {
+            (Outcome::Trap(_), None) => Ok(()),
+            (Outcome::Trap(got), Some(want)) if *got == Some(want) => Ok(()),
+            (_, want) => {
+                let want = want.map_or("trap".to_string(), |c| format!("trap: {}", c));
+                Err(TestError::Mismatch(format!(
+                    "expected {} from {}, got {}",
+                    want, self.invocation, outcome
+                )))
+            }
+        }
+    }
+}
+
+/// Parse the trap commands in the comments of the function in `context`, along with their
+/// lines. Malformed ones are pushed to `failures`.
+pub fn parse_trap_commands(
+    context: &Context,
+    signature: &Signature,
+    failures: &mut Failures,
+) -> Vec<(usize, TrapCommand)> {
+    let mut traps = Vec::new();
+    for comment in context.details.comments.iter() {
+        let line = context.line_of(comment.text);
+        match TrapCommand::parse(comment.text, signature) {
+            Ok(Some(trap)) => {
+                trace!("Parsed trap command: {}", comment.text);
+                traps.push((line, trap));
+            }
+            Ok(None) => {}
+            Err(message) => failures.push(context, line, TestError::Parse { line, message }),
+        }
+    }
+    traps
+}
+
+/// Build the vmctx struct in interpreter memory, with the same layout `test run` passes to
+/// compiled code (see [crate::runtest_environment::RuntestContext]), and return its address.
+fn register_heaps(state: &mut InterpreterState, test_env: &RuntestEnvironment) -> DataValue {
+    let vmctx_struct = test_env
+        .allocate_memory()
+        .into_iter()
+        // `RuntestEnvironment::parse` enforces the contiguous base/bound layout, so each heap
+        // takes the next two 64-bit slots.
+        .flat_map(|mem| {
+            let heap_len = mem.len() as u64;
+            let heap = state.register_heap(HeapInit::FromBacking(mem));
+            [
+                state.get_heap_address(I64, heap, 0).unwrap(),
+                state.get_heap_address(I64, heap, heap_len).unwrap(),
+            ]
+        })
+        .flat_map(|addr| {
+            let mut slot = [0u8; 8];
+            addr.write_to_slice(&mut slot[..]);
+            slot
+        })
+        .collect();
+
+    let vmctx_heap = state.register_heap(HeapInit::FromBacking(vmctx_struct));
+    state.get_heap_address(I64, vmctx_heap, 0).unwrap()
+}
+
+#[test]
+fn parse_trap_command() {
+    use cranelift_codegen::ir::{types::I32, AbiParam};
+    use cranelift_codegen::isa::CallConv;
+
+    let mut signature = Signature::new(CallConv::SystemV);
+    signature.params.push(AbiParam::new(I32));
+    signature.params.push(AbiParam::new(I32));
+
+    let trap = TrapCommand::parse("; trap: %div(1, 0) == int_divz", &signature)
+        .unwrap()
+        .unwrap();
+    assert_eq!(trap.invocation.func, "div");
+    assert_eq!(trap.invocation.args, [DataValue::I32(1), DataValue::I32(0)]);
+    assert_eq!(trap.code, Some(TrapCode::IntegerDivisionByZero));
+    assert!(trap.check(&Outcome::Trap(Some(TrapCode::IntegerDivisionByZero))).is_ok());
+    assert!(trap.check(&Outcome::Trap(Some(TrapCode::IntegerOverflow))).is_err());
+    assert!(trap.check(&Outcome::Return(vec![])).is_err());
+
+    let any = TrapCommand::parse("; trap: %div(1, 0)", &signature).unwrap().unwrap();
+    assert!(any.code.is_none());
+    assert!(any.check(&Outcome::Trap(None)).is_ok());
+
+    assert!(TrapCommand::parse("; run: %div(1, 1) == 1", &signature).unwrap().is_none());
+    assert!(TrapCommand::parse("; trap: %div(1, 0) == nope", &signature).is_err());
+}
+
+#[test]
+fn interpret_calls_between_functions() {
+    use crate::runone::{run_str, Options};
+
+    let summary = run_str(
+        concat!(
+            "test interpret\n",
+            "\n",
+            "function %one() -> i32 {\n",
+            "block0:\n",
+            "    v0 = iconst.i32 1\n",
+            "    return v0\n",
+            "}\n",
+            "; run: %one() == 1\n",
+            "\n",
+            "function %three() -> i32 {\n",
+            "    fn0 = %one() -> i32\n",
+            "\n",
+            "block0:\n",
+            "    v0 = call fn0()\n",
+            "    v1 = iadd_imm v0, 2\n",
+            "    return v1\n",
+            "}\n",
+            "; run: %three() == 3\n",
+        ),
+        &Options::default(),
+        None,
+        None,
+    )
+    .unwrap();
+    assert!(summary.failures.is_empty(), "{:?}", summary.failures);
+    assert_eq!(summary.passed, 2);
+}
@@ -0,0 +1,193 @@
+//! Logging for the test runner.
+//!
+//! The log level is configured with a spec in the same shape as `RUST_LOG`: a default level
+//! followed by optional `target=level` pairs, e.g. `warn,cranelift_codegen::machinst=trace`.
+//! The spec comes from `--log` or the `CRANELIFT_LOG` environment variable.
+//!
+//! Records normally go to stdout (stderr when stdout carries a blessed test) or to a log file. When per-function capture is enabled, the
+//! records emitted while a function is being tested are buffered instead, and are only shown
+//! if that function fails (see [capture]).
+
+use anyhow::{anyhow, Context as _};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Environment variable used when no `--log` flag is given.
+pub const LOG_ENV: &str = "CRANELIFT_LOG";
+
+/// Environment variable used when no `--log-file` flag is given.
+pub const LOG_FILE_ENV: &str = "CRANELIFT_LOG_FILE";
+
+/// A parsed log filter specification.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogSpec {
+    /// Level for targets that have no filter of their own.
+    default: LevelFilter,
+    /// Per-target filters. A filter applies to its target and every module below it.
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Default for LogSpec {
+    fn default() -> Self {
+        Self {
+            default: LevelFilter::Info,
+            targets: Vec::new(),
+        }
+    }
+}
+
+impl LogSpec {
+    /// Parse a spec such as `info,cranelift_codegen=warn,rvemu=off`.
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let mut out = Self::default();
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match part.split_once('=') {
+                Some((target, level)) => {
+                    let level = LevelFilter::from_str(level.trim())
+                        .map_err(|_| anyhow!("invalid log level `{}` for `{}`", level, target))?;
+                    out.targets.push((target.trim().to_string(), level));
+                }
+                None => match LevelFilter::from_str(part) {
+                    Ok(level) => out.default = level,
+                    // A bare target name enables everything for that target, like `RUST_LOG`.
+                    Err(_) => out.targets.push((part.to_string(), LevelFilter::Trace)),
+                },
+            }
+        }
+        Ok(out)
+    }
+
+    /// The level that applies to `target`, using the most specific matching filter.
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(prefix, _)| {
+                target == prefix
+                    || (target.starts_with(prefix.as_str())
+                        && target[prefix.len()..].starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// The most verbose level any target can log at.
+    fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, LevelFilter::max)
+    }
+}
+
+struct RunnerLogger {
+    spec: LogSpec,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+/// Set once at startup when per-function capture was requested.
+static CAPTURE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Records buffered for the function currently under test, if capturing.
+    static CAPTURED: RefCell<Option<String>> = RefCell::new(None);
+}
+
+impl Log for RunnerLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.spec.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!("{} {} - {}\n", record.level(), record.target(), record.args());
+        let captured = CAPTURED.with(|c| match c.borrow_mut().as_mut() {
+            Some(buf) => {
+                buf.push_str(&line);
+                true
+            }
+            None => false,
+        });
+        if !captured {
+            let _ = self.out.lock().unwrap().write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.out.lock().unwrap().flush();
+    }
+}
+
+/// Install the runner's logger.
+///
+/// `spec` and `file` fall back to [LOG_ENV] and [LOG_FILE_ENV] when not given. Without a file,
+/// records go to stdout, or to stderr with `stderr` set. With `capture_per_function` set,
+/// [capture] buffers records instead of writing them out.
+pub fn init(
+    spec: Option<&str>,
+    file: Option<&Path>,
+    stderr: bool,
+    capture_per_function: bool,
+) -> anyhow::Result<()> {
+    let spec = match spec {
+        Some(s) => LogSpec::parse(s)?,
+        None => match std::env::var(LOG_ENV) {
+            Ok(s) => LogSpec::parse(&s).with_context(|| format!("invalid {}", LOG_ENV))?,
+            Err(_) => LogSpec::default(),
+        },
+    };
+    let file = file
+        .map(Path::to_path_buf)
+        .or_else(|| std::env::var_os(LOG_FILE_ENV).map(Into::into));
+    let out: Box<dyn Write + Send> = match file {
+        Some(path) => Box::new(io::BufWriter::new(File::create(&path).with_context(|| {
+            format!("failed to create log file {}", path.display())
+        })?)),
+        None if stderr => Box::new(io::stderr()),
+        None => Box::new(io::stdout()),
+    };
+
+    let max_level = spec.max_level();
+    CAPTURE_ENABLED.store(capture_per_function, Ordering::Relaxed);
+    log::set_boxed_logger(Box::new(RunnerLogger {
+        spec,
+        out: Mutex::new(out),
+    }))
+    .map(|()| log::set_max_level(max_level))
+    .map_err(|e| anyhow!("{}", e))
+}
+
+/// Run `f`, buffering the records it logs if per-function capture is enabled.
+///
+/// Returns the result of `f` together with the captured log, which is `None` when capture is
+/// disabled or nothing was logged.
+pub fn capture<T>(f: impl FnOnce() -> T) -> (T, Option<String>) {
+    if !CAPTURE_ENABLED.load(Ordering::Relaxed) {
+        return (f(), None);
+    }
+    let previous = CAPTURED.with(|c| c.borrow_mut().replace(String::new()));
+    let result = f();
+    let captured = CAPTURED.with(|c| std::mem::replace(&mut *c.borrow_mut(), previous));
+    (result, captured.filter(|s| !s.is_empty()))
+}
+
+#[test]
+fn log_spec_parse() {
+    let spec = LogSpec::parse("warn,cranelift_codegen::machinst=trace,rvemu=off").unwrap();
+    assert_eq!(spec.level_for("emulator_run_ci"), LevelFilter::Warn);
+    assert_eq!(
+        spec.level_for("cranelift_codegen::machinst::lower"),
+        LevelFilter::Trace
+    );
+    assert_eq!(spec.level_for("cranelift_codegen::machinstx"), LevelFilter::Warn);
+    assert_eq!(spec.level_for("rvemu"), LevelFilter::Off);
+    assert_eq!(spec.max_level(), LevelFilter::Trace);
+    assert!(LogSpec::parse("rvemu=loud").is_err());
+}
@@ -7,44 +7,107 @@ use cranelift_reader::TestCommand;
 mod runtest_environment;
 mod test_run;
 use cranelift_codegen::isa::lookup_by_name;
+use cranelift_codegen::settings::Configurable;
 use cranelift_codegen::{ir, isa::TargetIsa};
 use std::fmt::format;
 use std::{borrow::Cow, path::Path};
 
 mod call;
+mod compile_cache;
+mod config;
+mod error;
+mod exit_code;
+mod interpret_trace;
 mod interpreter;
+mod logging;
+mod report;
+mod run_bless;
+mod test_alias_analysis;
+mod test_cat;
 mod test_compile;
-use log::{LevelFilter, Metadata, Record};
+mod test_dce;
+mod test_domtree;
+mod test_licm;
+mod test_optimize;
+mod test_print_cfg;
+mod test_simple_gvn;
+mod test_unwind;
+mod test_verifier;
 
-struct SimpleLogger(log::Level);
-
-static SIMPLE_LOGGER: SimpleLogger = SimpleLogger(log::Level::Trace);
-
-impl log::Log for SimpleLogger {
-    fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() >= self.0
-    }
-    fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            println!("{} - {}", record.level(), record.args());
+fn main() {
+    let mut log_spec = None;
+    let mut log_file = None;
+    let mut capture_log = false;
+    let mut backend_flags = Vec::new();
+    let mut files = Vec::new();
+    let mut options =
+        runone::Options::from_env().unwrap_or_else(|e| usage_error(format!("{:#}", e)));
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--log" => log_spec = Some(value(&mut args, "--log", "a spec")),
+            "--log-file" => log_file = Some(value(&mut args, "--log-file", "a path")),
+            "--capture-log" => capture_log = true,
+            "--bless" => options.bless = runone::BlessMode::InPlace,
+            _ if arg.starts_with("--bless=") => {
+                let mode = &arg["--bless=".len()..];
+                match runone::BlessMode::parse(mode) {
+                    Some(mode) => options.bless = mode,
+                    None => usage_error(format!("unknown bless mode `{}`", mode)),
+                }
+            }
+            _ if arg.starts_with("--bless-source=") => {
+                let source = &arg["--bless-source=".len()..];
+                match run_bless::BlessSource::parse(source) {
+                    Some(source) => options.bless_source = source,
+                    None => usage_error(format!("unknown bless source `{}`", source)),
+                }
+            }
+            "--strict" => options.strict = true,
+            "--compile-cache" => {
+                options.compile_cache =
+                    Some(value(&mut args, "--compile-cache", "a directory").into())
+            }
+            "--interpreter-fuel" => {
+                let fuel = value(&mut args, "--interpreter-fuel", "a number");
+                match fuel.parse() {
+                    Ok(fuel) => options.interpreter_fuel = fuel,
+                    Err(_) => usage_error(format!("invalid --interpreter-fuel `{}`", fuel)),
+                }
+            }
+            "--set" => backend_flags.push(value(&mut args, "--set", "name=value")),
+            // rvemu is the only emulator built in, so there is nothing to switch.
+            "--emulator" => {
+                let name = value(&mut args, "--emulator", "a name");
+                if config::Emulator::parse(&name).is_none() {
+                    usage_error(format!("unsupported emulator `{}`", name));
+                }
+            }
+            _ if arg.starts_with("--") => usage_error(format!("unknown argument `{}`", arg)),
+            _ => files.push(arg),
         }
     }
-    fn flush(&self) {}
-}
-
-fn init_logger() {
-    log::set_logger(&SIMPLE_LOGGER)
-        .map(|()| log::set_max_level(LevelFilter::Trace))
-        .unwrap()
-}
-
-fn main() {
-    init_logger();
-    let args: Vec<_> = std::env::args().collect();
-    if args.len() > 1 {
-        run_one_file(&Path::new(args[1].as_str()));
+    if files.len() > 1 {
+        usage_error(format!("expected one test file, got {}", files.len()));
+    }
+    // A test read from stdin is blessed to stdout, so everything else goes to stderr.
+    let from_stdin = files.first().map_or(false, |file| file == "-");
+    if let Err(e) = logging::init(
+        log_spec.as_deref(),
+        log_file.as_deref().map(Path::new),
+        from_stdin,
+        capture_log,
+    ) {
+        usage_error(format!("{:#}", e));
+    }
+    match parse_backend_flags(backend_flags) {
+        Ok(flags) => options.backend_flags = flags,
+        Err(e) => usage_error(format!("{:#}", e)),
+    }
+    if let Some(file) = files.first() {
+        run_one_file(&Path::new(file.as_str()), &options);
     } else {
-        run_one_file(&Path::new("xxx.clif"));
+        run_one_file(&Path::new("xxx.clif"), &options);
         // run_one_file(&Path::new(
         //     "../wasmtime/cranelift/filetests/filetests/runtests/alias.clif",
         // ));
@@ -69,9 +132,57 @@ fn main() {
     }
 }
 
-fn run_one_file(p: &Path) {
-    let x = runone::run(&p, None, None).unwrap();
-    println!("##################{:?} {:?}", p, x);
+/// Report a bad command line and exit, with the same exit code `run_all` uses for them.
+fn usage_error(message: String) -> ! {
+    eprintln!("run_one: {}", message);
+    std::process::exit(exit_code::OTHER)
+}
+
+/// The argument following `flag`, which must be given.
+fn value(args: &mut impl Iterator<Item = String>, flag: &str, what: &str) -> String {
+    args.next()
+        .unwrap_or_else(|| usage_error(format!("{} requires {}", flag, what)))
+}
+
+/// Run one test file. The path `-` reads the test from stdin instead, and reports on stderr
+/// so that stdout only gets the blessed test.
+fn run_one_file(p: &Path, options: &runone::Options) {
+    let from_stdin = p == Path::new("-");
+    let x = if from_stdin {
+        runone::run_stdin(options, None, None)
+    } else {
+        runone::run(&p, options, None, None)
+    };
+    log::logger().flush();
+    let report = |line: String| {
+        if from_stdin {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    };
+    match x {
+        Ok(summary) => {
+            for failure in &summary.failures {
+                eprintln!("{}", failure);
+            }
+            for reason in &summary.skips {
+                report(format!("skipped: {}", reason));
+            }
+            report(format!("##################{:?} {}", p, summary));
+            report(summary.counts().line());
+            if !summary.failures.is_empty() {
+                std::process::exit(summary.exit_code());
+            }
+        }
+        Err(e) => {
+            match e.downcast_ref::<error::Diagnostic>() {
+                Some(d) => eprintln!("{}", d),
+                None => eprintln!("{}: error: {:?}", p.display(), e),
+            }
+            std::process::exit(error::exit_code_of(&e));
+        }
+    }
 }
 
 /// Create a new subcommand trait object to match `parsed.command`.
@@ -83,19 +194,38 @@ fn new_subtest(parsed: &TestCommand) -> anyhow::Result<Box<dyn SubTest>> {
         "run" => test_run::subtest(parsed),
         "compile" => test_compile::subtest(parsed),
         "interpret" => interpreter::subtest(parsed),
+        "verifier" => test_verifier::subtest(parsed),
+        "optimize" => test_optimize::subtest(parsed),
+        "unwind" => test_unwind::subtest(parsed),
+        "cat" => test_cat::subtest(parsed),
+        "print-cfg" => test_print_cfg::subtest(parsed),
+        "domtree" => test_domtree::subtest(parsed),
+        "licm" => test_licm::subtest(parsed),
+        "dce" => test_dce::subtest(parsed),
+        "simple-gvn" => test_simple_gvn::subtest(parsed),
+        "alias-analysis" => test_alias_analysis::subtest(parsed),
         _ => subskip(parsed),
     }
 }
 
-struct TestSkip;
+/// Stands in for test commands this runner doesn't support, so they are reported as skipped.
+struct TestSkip {
+    reason: String,
+}
 
-pub fn subskip(_: &TestCommand) -> anyhow::Result<Box<dyn SubTest>> {
-    Ok(Box::new(TestSkip))
+pub fn subskip(parsed: &TestCommand) -> anyhow::Result<Box<dyn SubTest>> {
+    Ok(Box::new(TestSkip {
+        reason: format!("`{}` is not supported by this runner", parsed),
+    }))
 }
 
 impl SubTest for TestSkip {
     fn name(&self) -> &'static str {
-        "false test"
+        "skip"
+    }
+
+    fn needs_verifier(&self) -> bool {
+        false
     }
 
     fn is_mutating(&self) -> bool {
@@ -106,8 +236,12 @@ impl SubTest for TestSkip {
         false
     }
 
-    fn run(&self, func: Cow<ir::Function>, context: &Context) -> anyhow::Result<()> {
-        Ok(())
+    fn skip_reason(&self) -> Option<String> {
+        Some(self.reason.clone())
+    }
+
+    fn run(&self, _func: Cow<ir::Function>, _context: &Context) -> anyhow::Result<()> {
+        unreachable!("skipped tests are never run")
     }
 }
 
@@ -119,9 +253,29 @@ fn pretty_anyhow_error(
     anyhow::anyhow!("{}", s)
 }
 
-pub fn build_backend() -> Box<dyn TargetIsa> {
+/// Parse `name=value` Cranelift settings into the `(name, value)` pairs [build_backend] takes.
+fn parse_backend_flags(flags: Vec<String>) -> anyhow::Result<Vec<(String, String)>> {
+    let mut parsed = Vec::with_capacity(flags.len());
+    let mut check = cranelift_codegen::settings::builder();
+    for flag in flags {
+        let (name, value) = flag
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected `name=value`, got `{}`", flag))?;
+        check
+            .set(name, value)
+            .map_err(|e| anyhow::anyhow!("invalid setting `{}`: {}", flag, e))?;
+        parsed.push((name.to_string(), value.to_string()));
+    }
+    Ok(parsed)
+}
+
+/// The riscv64gc backend with the Cranelift settings `flags`, see [runone::Options].
+pub fn build_backend(flags: &[(String, String)]) -> Box<dyn TargetIsa> {
     let builder = lookup_by_name("riscv64gc").unwrap();
-    let shared_builder = cranelift_codegen::settings::builder();
+    let mut shared_builder = cranelift_codegen::settings::builder();
+    for (name, value) in flags {
+        shared_builder.set(name, value).unwrap();
+    }
     let shared_flags = cranelift_codegen::settings::Flags::new(shared_builder);
     let isa = builder.finish(shared_flags).unwrap();
     isa
@@ -129,12 +283,16 @@ pub fn build_backend() -> Box<dyn TargetIsa> {
 
 #[test]
 fn one_by_one_run_sh() {
-    let s: Vec<_> =
-        std::fs::read_dir("/home/yuyang/projects/wasmtime/cranelift/filetests/filetests/runtests")
-            .unwrap()
-            .into_iter()
-            .map(|r| r.unwrap().file_name())
-            .collect();
+    let config = config::Config::load(None).unwrap();
+    let s: Vec<_> = std::fs::read_dir(
+        config
+            .wasmtime
+            .join("cranelift/filetests/filetests/runtests"),
+    )
+    .unwrap()
+    .into_iter()
+    .map(|r| r.unwrap().file_name())
+    .collect();
 
     let mut script = String::from("");
 
@@ -191,7 +349,7 @@ fn xxx() {
         file.write_all(file_content.as_bytes())
             .expect("write failed");
         file.sync_all().unwrap();
-        run_one_file(Path::new(file_name));
+        run_one_file(Path::new(file_name), &runone::Options::default());
     }
 }
 
@@ -0,0 +1,58 @@
+//! The machine-readable summary line `run_one` prints, so `run_all` can total up results.
+//!
+//! This module has no dependencies so both binaries can include it.
+
+const PREFIX: &str = "run_one summary:";
+
+/// Test counts of one or more test files.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+impl Counts {
+    /// The summary line for these counts.
+    pub fn line(&self) -> String {
+        format!(
+            "{} passed={} failed={} skipped={}",
+            PREFIX, self.passed, self.failed, self.skipped
+        )
+    }
+
+    /// Find the summary line in the output of `run_one`.
+    pub fn parse(output: &str) -> Option<Counts> {
+        let line = output.lines().find_map(|l| l.strip_prefix(PREFIX))?;
+        let mut counts = Counts::default();
+        for field in line.split_whitespace() {
+            let (name, value) = field.split_once('=')?;
+            let value = value.parse().ok()?;
+            match name {
+                "passed" => counts.passed = value,
+                "failed" => counts.failed = value,
+                "skipped" => counts.skipped = value,
+                _ => {}
+            }
+        }
+        Some(counts)
+    }
+
+    pub fn add(&mut self, other: Counts) {
+        self.passed += other.passed;
+        self.failed += other.failed;
+        self.skipped += other.skipped;
+    }
+}
+
+#[test]
+fn summary_line_round_trip() {
+    let counts = Counts {
+        passed: 3,
+        failed: 1,
+        skipped: 2,
+    };
+    let output = format!("some output\n{}\nmore output\n", counts.line());
+    assert_eq!(Counts::parse(&output), Some(counts));
+    assert_eq!(Counts::parse("no summary here"), None);
+}
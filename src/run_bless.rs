@@ -0,0 +1,191 @@
+//! Checking and blessing of `; run:` expectations.
+//!
+//! `test run` and `test interpret` both go through [check_run_commands], differing only in how
+//! they call the function. When blessing is enabled (see [crate::runone::BlessMode]), they
+//! rewrite the expected value of every `; run: %f(...) == X` line that doesn't match with the
+//! actual result. Only one of them acts as the source of truth for a given run, chosen with
+//! `--bless-source` or [BLESS_SOURCE_ENV]; the other keeps checking the results as usual.
+
+use crate::error::{Failures, TestError};
+use crate::Context;
+use cranelift_codegen::data_value::DataValue;
+use cranelift_codegen::ir::Signature;
+use cranelift_reader::{parse_run_command, Comment, Comparison, Invocation, RunCommand};
+use log::trace;
+
+/// Environment variable choosing the source of truth when no `--bless-source` flag is given.
+pub const BLESS_SOURCE_ENV: &str = "CRANELIFT_BLESS_SOURCE";
+
+/// The subtest whose results are written back into the test file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlessSource {
+    /// `test interpret`, running the function in the Cranelift interpreter.
+    Interpreter,
+    /// `test run`, running the compiled function in the emulator.
+    Emulator,
+}
+
+impl BlessSource {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "interpreter" | "interpret" => Some(BlessSource::Interpreter),
+            "emulator" | "run" => Some(BlessSource::Emulator),
+            _ => None,
+        }
+    }
+
+    /// Should the subtest acting as `self` rewrite the run lines of the test in `context`?
+    /// The interpreter is the default source, being the reference the emulator results are
+    /// checked against.
+    pub fn is_blessing(self, context: &Context) -> bool {
+        context.file_update.is_enabled() && context.options.bless_source == self
+    }
+}
+
+/// A `; run:` or `; print:` command of the function under test.
+pub struct RunLine<'a, 'c> {
+    pub comment: &'c Comment<'a>,
+    pub line: usize,
+    pub command: RunCommand,
+}
+
+/// Parse the run commands in the comments of the function in `context`. Malformed ones are
+/// pushed to `failures`.
+pub fn parse_run_commands<'a, 'c>(
+    context: &'c Context<'a>,
+    signature: &Signature,
+    failures: &mut Failures,
+) -> Vec<RunLine<'a, 'c>> {
+    let mut commands = Vec::new();
+    for comment in context.details.comments.iter() {
+        let line = context.line_of(comment.text);
+        match parse_run_command(comment.text, signature) {
+            Ok(Some(command)) => {
+                trace!("Parsed run command: {}", command);
+                commands.push(RunLine {
+                    comment,
+                    line,
+                    command,
+                });
+            }
+            Ok(None) => {}
+            Err(e) => {
+                let error = TestError::Parse {
+                    line,
+                    message: e.message,
+                };
+                failures.push(context, line, error);
+            }
+        }
+    }
+    commands
+}
+
+/// Call the function for each of `commands` with `call`, then check the result against the
+/// command's expectation, or bless it if `source` is blessing.
+///
+/// Errors from `call` are reported on the command's line. Keeps going after a failing command
+/// so they are all reported along with the earlier `failures`.
+pub fn check_run_commands<'a>(
+    context: &Context<'a>,
+    commands: Vec<RunLine<'a, '_>>,
+    source: BlessSource,
+    mut failures: Failures,
+    mut call: impl FnMut(&Invocation, usize) -> anyhow::Result<Vec<DataValue>>,
+) -> anyhow::Result<()> {
+    let blessing = source.is_blessing(context);
+    let mut updates = RunUpdates::default();
+    for RunLine {
+        comment,
+        line,
+        command,
+    } in commands
+    {
+        let invocation = match &command {
+            RunCommand::Print(invocation) | RunCommand::Run(invocation, _, _) => invocation,
+        };
+        // Call the function before handing the result to `RunCommand::run`, which only
+        // reports errors as strings, so a trap or an emulator fault stays distinct from a
+        // result mismatch.
+        let actual = match call(invocation, line) {
+            Ok(actual) => actual,
+            Err(e) => {
+                failures.push(context, line, e);
+                continue;
+            }
+        };
+        if blessing && updates.record(comment, &command, &actual) {
+            continue;
+        }
+        if let Err(message) = command.run(|_, _| Ok(actual)) {
+            failures.push(context, line, TestError::Mismatch(message));
+        }
+    }
+    updates.apply(context)?;
+    failures.into_result()
+}
+
+/// The rewritten `; run:` lines of one function.
+#[derive(Default)]
+pub struct RunUpdates<'a> {
+    /// Pairs of the original comment text and its replacement, in file order.
+    edits: Vec<(&'a str, String)>,
+}
+
+impl<'a> RunUpdates<'a> {
+    /// Record that `command`, parsed from `comment`, produced `actual`.
+    ///
+    /// Returns `true` if the expectation was rewritten, in which case the command shouldn't be
+    /// checked. Only `==` expectations can be rewritten; anything else is left for the caller
+    /// to check as usual.
+    pub fn record(
+        &mut self,
+        comment: &Comment<'a>,
+        command: &RunCommand,
+        actual: &[DataValue],
+    ) -> bool {
+        let invocation = match command {
+            RunCommand::Run(invocation, Comparison::Equals, expected) if expected != actual => {
+                invocation
+            }
+            _ => return false,
+        };
+        let text = comment.text;
+        let prefix = &text[..text.find("run").unwrap_or(0)];
+        let updated = RunCommand::Run(invocation.clone(), Comparison::Equals, actual.to_vec());
+        self.edits.push((text, format!("{}{}", prefix, updated)));
+        true
+    }
+
+    /// Splice the rewritten lines into the test file.
+    ///
+    /// Only run lines in the expectation region after the function can be rewritten. Fails if
+    /// any recorded line isn't there, since its mismatch would otherwise go unreported.
+    pub fn apply(self, context: &Context) -> anyhow::Result<()> {
+        if self.edits.is_empty() {
+            return Ok(());
+        }
+        let mut edits = self.edits.into_iter().peekable();
+        context
+            .file_update
+            .update_at(&context.details.location, |region| {
+                for line in region.iter_mut() {
+                    match edits.peek() {
+                        Some((old, new)) if line.trim() == old.trim() => {
+                            let indent = &line[..line.len() - line.trim_start().len()];
+                            *line = format!("{}{}", indent, new);
+                            edits.next();
+                        }
+                        _ => {}
+                    }
+                }
+            })?;
+        if let Some((old, _)) = edits.next() {
+            anyhow::bail!(
+                "cannot bless `{}`: only run lines right after the function can be updated",
+                old.trim()
+            );
+        }
+        Ok(())
+    }
+}
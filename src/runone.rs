@@ -1,6 +1,14 @@
 //! Run the tests in a single test file.
 
+use crate::compile_cache;
+use crate::error::{Diagnostic, Failures, TestError};
+use crate::exit_code;
+use crate::interpret_trace;
+use crate::interpreter;
+use crate::logging;
+use crate::report::Counts;
 use crate::new_subtest;
+use crate::run_bless::{BlessSource, BLESS_SOURCE_ENV};
 use crate::{Context, SubTest};
 use anyhow::{bail, Context as _, Result};
 use cranelift_codegen::ir::Function;
@@ -12,44 +20,238 @@ use cranelift_codegen::verify_function;
 use cranelift_reader::{parse_test, IsaSpec, Location, ParseOptions};
 use log::info;
 use std::borrow::Cow;
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::fmt;
 use std::fs;
+use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::str::Lines;
 use std::time;
 use target_lexicon::triple;
 
+/// Environment variable that turns on strict mode when no `--strict` flag is given.
+pub const STRICT_ENV: &str = "CRANELIFT_TEST_STRICT";
+
+/// How test files are run. `run_one` takes these from its command line, falling back to the
+/// environment variables `run_all` forwards its settings in.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// What to do with expectations that don't match, see [BlessMode].
+    pub bless: BlessMode,
+    /// Which of `test run` and `test interpret` gets to bless `; run:` lines.
+    pub bless_source: BlessSource,
+    /// Count skipped tests as failures.
+    pub strict: bool,
+    /// Instructions a single interpreted run command may execute; `0` removes the limit.
+    pub interpreter_fuel: u64,
+    /// Trace every interpreted run command, as if each `test interpret` had the `trace` option.
+    pub interpreter_trace: bool,
+    /// Directory compiled functions are cached in, see [crate::compile_cache].
+    pub compile_cache: Option<PathBuf>,
+    /// Cranelift settings for the backend functions are compiled with, see
+    /// [crate::build_backend].
+    pub backend_flags: Vec<(String, String)>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            bless: BlessMode::Off,
+            bless_source: BlessSource::Interpreter,
+            strict: false,
+            interpreter_fuel: interpreter::DEFAULT_FUEL,
+            interpreter_trace: false,
+            compile_cache: None,
+            backend_flags: Vec::new(),
+        }
+    }
+}
+
+impl Options {
+    /// The options set in the environment, with the defaults for the rest.
+    pub fn from_env() -> Result<Self> {
+        let var = |name| std::env::var(name).ok();
+        let defaults = Options::default();
+        let bless = match var(BLESS_ENV) {
+            Some(s) => match BlessMode::parse(&s) {
+                Some(mode) => mode,
+                None => bail!("unknown bless mode `{}` in {}", s, BLESS_ENV),
+            },
+            None => defaults.bless,
+        };
+        let bless_source = match var(BLESS_SOURCE_ENV) {
+            Some(s) => match BlessSource::parse(&s) {
+                Some(source) => source,
+                None => bail!("unknown bless source `{}` in {}", s, BLESS_SOURCE_ENV),
+            },
+            None => defaults.bless_source,
+        };
+        Ok(Options {
+            bless,
+            bless_source,
+            strict: var(STRICT_ENV).map_or(defaults.strict, |v| v == "1"),
+            interpreter_fuel: var(interpreter::FUEL_ENV)
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(defaults.interpreter_fuel),
+            interpreter_trace: var(interpret_trace::TRACE_ENV)
+                .map_or(defaults.interpreter_trace, |v| !v.is_empty() && v != "0"),
+            compile_cache: std::env::var_os(compile_cache::CACHE_ENV)
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from),
+            backend_flags: defaults.backend_flags,
+        })
+    }
+}
+
+/// Name used for tests read from stdin or passed in as a string.
+pub const STDIN_NAME: &str = "<stdin>";
+
 /// Load `path` and run the test in it.
 ///
 /// If running this test causes a panic, it will propagate as normal.
 pub fn run(
     path: &Path,
+    options: &Options,
     passes: Option<&[String]>,
     target: Option<&str>,
-) -> anyhow::Result<time::Duration> {
+) -> anyhow::Result<FileSummary> {
     let _tt = timing::process_file();
     info!("---\nFile: {}", path.to_string_lossy());
     let started = time::Instant::now();
     let buffer =
         fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
-    let options = ParseOptions {
+    let mode = options.bless;
+    let file_update = match mode {
+        // Collect the edits in memory and turn them into a patch at the end.
+        BlessMode::Patch => FileUpdate::in_memory(&path.to_string_lossy(), &buffer, mode),
+        _ => FileUpdate::new(&path, &buffer, mode),
+    };
+    let mut summary = FileSummary::default();
+    let result = run_buffer(
+        &buffer,
+        &path.to_string_lossy(),
+        &file_update,
+        options,
+        passes,
+        target,
+        &mut summary,
+    );
+    // Keep the expectations that were updated even if some test failed.
+    file_update.flush()?;
+    if let Some(updated) = file_update.updated_text() {
+        let patch_path = patch_path(path);
+        let name = path.to_string_lossy();
+        fs::write(&patch_path, unified_diff(&buffer, &updated, &name, &name))
+            .with_context(|| format!("failed to write {}", patch_path.display()))?;
+        println!("wrote {}", patch_path.display());
+    }
+    result?;
+    summary.elapsed = started.elapsed();
+    Ok(summary)
+}
+
+/// Where [BlessMode::Patch] writes the patch for the test file at `path`.
+pub fn patch_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".patch");
+    PathBuf::from(name)
+}
+
+/// A unified diff turning `old` into `new`.
+pub fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    similar::TextDiff::from_lines(old, new)
+        .unified_diff()
+        .header(old_name, new_name)
+        .to_string()
+}
+
+/// Run the test contained in `text`, which did not come from a file on disk.
+///
+/// Test expectations cannot be updated in place here, so when blessing, the resulting test
+/// file (or, with [BlessMode::Patch], the patch) is printed to stdout instead.
+pub fn run_str(
+    text: &str,
+    options: &Options,
+    passes: Option<&[String]>,
+    target: Option<&str>,
+) -> anyhow::Result<FileSummary> {
+    let _tt = timing::process_file();
+    info!("---\nFile: {}", STDIN_NAME);
+    let started = time::Instant::now();
+    let mode = options.bless;
+    let file_update = FileUpdate::in_memory(STDIN_NAME, text, mode);
+    let mut summary = FileSummary::default();
+    let result = run_buffer(
+        text,
+        STDIN_NAME,
+        &file_update,
+        options,
+        passes,
+        target,
+        &mut summary,
+    );
+    file_update.flush()?;
+    if let Some(updated) = file_update.updated_text() {
+        match mode {
+            BlessMode::Patch => print!("{}", unified_diff(text, &updated, STDIN_NAME, STDIN_NAME)),
+            _ => print!("{}", updated),
+        }
+    }
+    result?;
+    summary.elapsed = started.elapsed();
+    Ok(summary)
+}
+
+/// Read a test file from stdin and run it with [run_str].
+pub fn run_stdin(
+    options: &Options,
+    passes: Option<&[String]>,
+    target: Option<&str>,
+) -> anyhow::Result<FileSummary> {
+    let mut buffer = String::new();
+    std::io::stdin()
+        .read_to_string(&mut buffer)
+        .context("failed to read test from stdin")?;
+    run_str(&buffer, options, passes, target)
+}
+
+fn run_buffer(
+    buffer: &str,
+    file_path: &str,
+    file_update: &FileUpdate,
+    options: &Options,
+    passes: Option<&[String]>,
+    target: Option<&str>,
+    summary: &mut FileSummary,
+) -> anyhow::Result<()> {
+    let parse_options = ParseOptions {
         target,
         passes,
         ..ParseOptions::default()
     };
-    let testfile = match parse_test(&buffer, options) {
+    let testfile = match parse_test(&buffer, parse_options) {
         Ok(testfile) => testfile,
         Err(e) => {
             if e.is_warning {
-                println!(
+                info!(
                     "skipping test {:?} (line {}): {}",
-                    path, e.location.line_number, e.message
+                    file_path, e.location.line_number, e.message
                 );
-                return Ok(started.elapsed());
+                let reason = format!("whole file: {}", e.message);
+                if options.strict {
+                    let error = TestError::Skipped(reason).into();
+                    return Err(Diagnostic::new(file_path, e.location.line_number, error).into());
+                }
+                summary.skipped += 1;
+                summary.skips.push(reason);
+                return Ok(());
             }
-            return Err(e)
-                .context(format!("failed to parse {}", path.display()))
-                .into();
+            let line = e.location.line_number;
+            let error = TestError::Parse {
+                line,
+                message: e.message,
+            };
+            return Err(Diagnostic::new(file_path, line, error.into()).into());
         }
     };
 
@@ -85,27 +287,162 @@ pub fn run(
         Some(t) => t,
     };
 
-    let mut file_update = FileUpdate::new(&path);
-    let file_path = path.to_string_lossy();
+    let timings = Timings::default();
+
+    // Every function in the file, for subtests that follow calls between them.
+    let functions: Vec<_> = testfile.functions.iter().map(|(f, _)| f.clone()).collect();
+
     for (func, details) in testfile.functions {
         let mut context = Context {
             preamble_comments: &testfile.preamble_comments,
             details,
+            functions: &functions,
+            timings: &timings,
+            options,
             verified: false,
             flags,
             isa: None,
-            file_path: file_path.as_ref(),
-            file_update: &mut file_update,
+            file_path,
+            source: buffer,
+            file_update,
         };
 
-        for tuple in &tuples {
-            run_one_test(*tuple, Cow::Borrowed(&func), &mut context)?;
+        let line_number = context.details.location.line_number;
+        let (failures, log) = logging::capture(|| {
+            let mut failures = Vec::new();
+            let mut record = |result: anyhow::Result<()>| -> bool {
+                let e = match result {
+                    Ok(()) => {
+                        summary.passed += 1;
+                        return true;
+                    }
+                    Err(e) => e,
+                };
+                if !options.strict {
+                    if let Some(TestError::Skipped(reason)) = e.downcast_ref::<TestError>() {
+                        summary.skipped += 1;
+                        if !summary.skips.contains(reason) {
+                            summary.skips.push(reason.clone());
+                        }
+                        return true;
+                    }
+                }
+                summary.failed += 1;
+                // A function the verifier rejects isn't worth running any further tests on.
+                let keep_going = !e
+                    .chain()
+                    .any(|e| matches!(e.downcast_ref::<TestError>(), Some(TestError::Verifier(_))));
+                match e.downcast::<Failures>() {
+                    Ok(Failures(diagnostics)) => failures.extend(diagnostics),
+                    Err(e) => failures.push(Diagnostic::new(file_path, line_number, e)),
+                }
+                keep_going
+            };
+            let mut keep_going = true;
+            for tuple in &tuples {
+                keep_going = record(run_one_test(*tuple, Cow::Borrowed(&func), &mut context));
+                if !keep_going {
+                    break;
+                }
+            }
+            // Run the last test with an owned function which means it won't need to clone it
+            // before mutating.
+            if keep_going {
+                record(run_one_test(last_tuple, Cow::Owned(func), &mut context));
+            }
+            failures
+        });
+        // The captured log is only worth showing when the function failed.
+        let mut failures = failures.into_iter();
+        if let Some(mut first) = failures.next() {
+            first.log = log;
+            summary.failures.push(first);
+            summary.failures.extend(failures);
+        }
+    }
+    summary.compile_time += timings.compile.get();
+    summary.execute_time += timings.execute.get();
+    Ok(())
+}
+
+/// The outcome of running one test file.
+///
+/// Counts are of (subtest, function) pairs; each failing pair contributes one or more
+/// [Diagnostic]s, e.g. one per failing run command.
+#[derive(Debug, Default)]
+pub struct FileSummary {
+    pub elapsed: time::Duration,
+    /// Part of `elapsed` spent compiling functions to run them, see [Timings].
+    pub compile_time: time::Duration,
+    /// Part of `elapsed` spent running compiled functions.
+    pub execute_time: time::Duration,
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub failures: Vec<Diagnostic>,
+    /// Why tests were skipped, without duplicates.
+    pub skips: Vec<String>,
+}
+
+impl FileSummary {
+    pub fn counts(&self) -> Counts {
+        Counts {
+            passed: self.passed,
+            failed: self.failed,
+            skipped: self.skipped,
         }
-        // Run the last test with an owned function which means it won't need to clone it before
-        // mutating.
-        run_one_test(last_tuple, Cow::Owned(func), &mut context)?;
     }
-    Ok(started.elapsed())
+
+    /// The exit code of the first failure, or success.
+    pub fn exit_code(&self) -> i32 {
+        self.failures
+            .first()
+            .map_or(exit_code::SUCCESS, Diagnostic::exit_code)
+    }
+}
+
+impl fmt::Display for FileSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} passed, {} failed, {} skipped in {:?}",
+            self.passed, self.failed, self.skipped, self.elapsed
+        )?;
+        if !self.compile_time.is_zero() || !self.execute_time.is_zero() {
+            write!(
+                f,
+                " (compile {:?}, execute {:?})",
+                self.compile_time, self.execute_time
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Time subtests spend compiling and running functions, summed over a test file.
+#[derive(Debug, Default)]
+pub struct Timings {
+    pub compile: Cell<time::Duration>,
+    pub execute: Cell<time::Duration>,
+}
+
+impl Timings {
+    /// Run `f`, adding the time it takes to the compile time.
+    pub fn compile<T>(&self, f: impl FnOnce() -> T) -> T {
+        Self::measure(&self.compile, f)
+    }
+
+    /// Run `f`, adding the time it takes to the execution time.
+    pub fn execute<T>(&self, f: impl FnOnce() -> T) -> T {
+        Self::measure(&self.execute, f)
+    }
+
+    fn measure<T>(total: &Cell<time::Duration>, f: impl FnOnce() -> T) -> T {
+        let started = time::Instant::now();
+        let result = f();
+        total.set(total.get() + started.elapsed());
+        result
+    }
 }
 
 // Given a slice of tests, generate a vector of (test, flags, isa) tuples.
@@ -150,10 +487,14 @@ fn run_one_test<'a>(
     context.flags = flags;
     context.isa = isa;
 
+    if let Some(reason) = test.skip_reason() {
+        return Err(TestError::Skipped(reason).into());
+    }
+
     // Should we run the verifier before this test?
     if !context.verified && test.needs_verifier() {
         verify_function(&func, context.flags_or_isa())
-            .map_err(|errors| anyhow::anyhow!("{}", pretty_verifier_error(&func, None, errors)))?;
+            .map_err(|errors| TestError::Verifier(pretty_verifier_error(&func, None, errors)))?;
         context.verified = true;
     }
 
@@ -161,87 +502,268 @@ fn run_one_test<'a>(
     Ok(())
 }
 
+/// Environment variable that enables blessing when no `--bless` flag is given: `1` updates
+/// test files in place, `patch` writes patches next to them.
+pub const BLESS_ENV: &str = "CRANELIFT_TEST_BLESS";
+
+/// What to do with test expectations that don't match the actual output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlessMode {
+    /// Report the mismatch as a test failure.
+    Off,
+    /// Rewrite the test file with the actual output.
+    InPlace,
+    /// Leave the test file alone and write the rewrite as a `.patch` file next to it.
+    Patch,
+}
+
+impl BlessMode {
+    /// Parse the value of `--bless=<mode>` or [BLESS_ENV].
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "" | "0" | "off" => Some(BlessMode::Off),
+            "1" | "in-place" => Some(BlessMode::InPlace),
+            "patch" => Some(BlessMode::Patch),
+            _ => None,
+        }
+    }
+}
+
 /// A helper struct to update a file in-place as test expectations are
 /// automatically updated.
 ///
-/// This structure automatically handles multiple edits to one file. Our edits
-/// are line-based but if editing a previous portion of the file adds lines then
-/// all future edits need to know to skip over those previous lines. Note that
-/// this assumes that edits are done front-to-back.
+/// Edits are line-based and apply to the expectation region of a function: the blank and
+/// comment lines between its closing `}` and the next real line. They are keyed by the
+/// function's line in the original file, so several subtests may edit the same function and
+/// edits may arrive in any order. Nothing is written until [FileUpdate::flush], which writes
+/// all edits at once. Lines outside the edited regions are kept byte for byte, and new lines
+/// use the file's line ending.
 pub struct FileUpdate {
-    path: PathBuf,
-    line_diff: Cell<isize>,
-    last_update: Cell<usize>,
+    /// Where the edited text goes on [FileUpdate::flush]. `None` keeps it in memory, either
+    /// because the test has no file on disk (e.g. it was read from stdin) or because the edits
+    /// become a patch.
+    path: Option<PathBuf>,
+    name: String,
+    mode: BlessMode,
+    /// The original test text.
+    original: String,
+    /// Edited expectation regions, keyed by the line number of their function.
+    regions: RefCell<BTreeMap<usize, Region>>,
+    /// For in-memory updates, the text produced by the last [FileUpdate::flush] that changed
+    /// anything. Always `None` when the edits are written to `path`.
+    updated: RefCell<Option<String>>,
+}
+
+/// The expectation region of one function, see [FileUpdate].
+struct Region {
+    /// Range of original lines (0-based) the region replaces.
+    start: usize,
+    end: usize,
+    /// The edited lines, without line endings.
+    lines: Vec<String>,
 }
 
 impl FileUpdate {
-    fn new(path: &Path) -> FileUpdate {
+    fn new(path: &Path, text: &str, mode: BlessMode) -> FileUpdate {
+        FileUpdate {
+            path: Some(path.to_path_buf()),
+            ..FileUpdate::in_memory(&path.to_string_lossy(), text, mode)
+        }
+    }
+
+    fn in_memory(name: &str, text: &str, mode: BlessMode) -> FileUpdate {
         FileUpdate {
-            path: path.to_path_buf(),
-            line_diff: Cell::new(0),
-            last_update: Cell::new(0),
+            path: None,
+            name: name.to_string(),
+            mode,
+            original: text.to_string(),
+            regions: RefCell::new(BTreeMap::new()),
+            updated: RefCell::new(None),
         }
     }
 
-    /// Updates the file that this structure references at the `location`
-    /// specified.
+    /// Should subtests update their expectations rather than fail on a mismatch?
+    pub fn is_enabled(&self) -> bool {
+        self.mode != BlessMode::Off
+    }
+
+    /// The edited test text of an in-memory update, if any edit was made. Updates of a file on
+    /// disk have already been written there by [FileUpdate::flush] and return `None`.
+    fn updated_text(&self) -> Option<String> {
+        self.updated.borrow().clone()
+    }
+
+    /// Updates the expectation region of the function at `location`.
     ///
-    /// The closure `f` is given first a buffer to push the new test into along
-    /// with a lines iterator for the old test.
-    pub fn update_at(
-        &self,
-        location: &Location,
-        f: impl FnOnce(&mut String, &mut Lines<'_>),
-    ) -> Result<()> {
-        // This is required for correctness of this update.
-        assert!(location.line_number > self.last_update.get());
-        self.last_update.set(location.line_number);
-
-        // Read the old test file and calculate thte new line number we're
-        // preserving up to based on how many lines prior to this have been
-        // removed or added.
-        let old_test = std::fs::read_to_string(&self.path)?;
-        let mut new_test = String::new();
-        let mut lines = old_test.lines();
-        let lines_to_preserve =
-            (((location.line_number - 1) as isize) + self.line_diff.get()) as usize;
-
-        // Push everything leading up to the start of the function
-        for _ in 0..lines_to_preserve {
-            new_test.push_str(lines.next().unwrap());
-            new_test.push_str("\n");
-        }
-
-        // Push the whole function, leading up to the trailing `}`
-        let mut first = true;
-        while let Some(line) = lines.next() {
-            if first && !line.starts_with("function") {
-                bail!(
-                    "line {} in test file {:?} did not start with `function`, \
-                     cannot automatically update test",
-                    location.line_number,
-                    self.path,
-                );
+    /// The closure `f` is given the current lines of the region, including any edits made by
+    /// earlier updates, and edits them in place.
+    pub fn update_at(&self, location: &Location, f: impl FnOnce(&mut Vec<String>)) -> Result<()> {
+        if !self.is_enabled() {
+            bail!("updating test expectations requires --bless or {}", BLESS_ENV);
+        }
+        let mut regions = self.regions.borrow_mut();
+        let region = match regions.entry(location.line_number) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(self.find_region(location.line_number)?),
+        };
+        f(&mut region.lines);
+        Ok(())
+    }
+
+    /// Locate the expectation region of the function starting on `line_number`.
+    fn find_region(&self, line_number: usize) -> Result<Region> {
+        let lines: Vec<&str> = self.original.lines().collect();
+        let first = line_number - 1;
+        if !lines.get(first).map_or(false, |l| l.starts_with("function")) {
+            bail!(
+                "line {} in test file {} did not start with `function`, \
+                 cannot automatically update test",
+                line_number,
+                self.name,
+            );
+        }
+        // The function ends at the first line starting with `}`.
+        let start = match lines[first..].iter().position(|l| l.starts_with('}')) {
+            Some(i) => first + i + 1,
+            None => bail!(
+                "function on line {} in test file {} has no closing `}}`",
+                line_number,
+                self.name
+            ),
+        };
+        let end = lines[start..]
+            .iter()
+            .position(|l| !(l.trim().is_empty() || l.trim_start().starts_with(';')))
+            .map_or(lines.len(), |i| start + i);
+        Ok(Region {
+            start,
+            end,
+            lines: lines[start..end].iter().map(|l| l.to_string()).collect(),
+        })
+    }
+
+    /// Write out all edits made so far.
+    ///
+    /// Files on disk are replaced atomically by writing a temporary file next to them and
+    /// renaming it over the original.
+    pub fn flush(&self) -> Result<()> {
+        let regions = self.regions.borrow();
+        let new_test = self.apply(&regions);
+        if new_test == self.original {
+            return Ok(());
+        }
+        match &self.path {
+            Some(path) => {
+                let mut tmp = path.as_os_str().to_owned();
+                tmp.push(".bless.tmp");
+                let tmp = PathBuf::from(tmp);
+                fs::write(&tmp, &new_test)
+                    .with_context(|| format!("failed to write {}", tmp.display()))?;
+                fs::rename(&tmp, path)
+                    .with_context(|| format!("failed to replace {}", path.display()))?;
             }
-            first = false;
-            new_test.push_str(line);
-            new_test.push_str("\n");
-            if line.starts_with("}") {
-                break;
+            None => *self.updated.borrow_mut() = Some(new_test),
+        }
+        Ok(())
+    }
+
+    /// The original text with every edited region spliced in.
+    fn apply(&self, regions: &BTreeMap<usize, Region>) -> String {
+        let newline = if self.original.contains("\r\n") {
+            "\r\n"
+        } else {
+            "\n"
+        };
+        // Keep each line's own ending so untouched lines come out exactly as they went in.
+        let lines: Vec<&str> = self.original.split_inclusive('\n').collect();
+        let mut regions: Vec<&Region> = regions.values().collect();
+        regions.sort_by_key(|r| r.start);
+
+        let mut new_test = String::with_capacity(self.original.len());
+        let mut next = 0;
+        for region in regions {
+            for line in &lines[next..region.start] {
+                new_test.push_str(line);
+            }
+            // A function at the very end of a file without a trailing newline.
+            if region.start > 0 && !new_test.ends_with('\n') && !region.lines.is_empty() {
+                new_test.push_str(newline);
+            }
+            for line in &region.lines {
+                new_test.push_str(line);
+                new_test.push_str(newline);
             }
+            next = region.end;
         }
+        for line in &lines[next..] {
+            new_test.push_str(line);
+        }
+        new_test
+    }
+}
 
-        // Use our custom update function to further update the test.
-        f(&mut new_test, &mut lines);
+#[test]
+fn bless_mode_and_patch() {
+    assert_eq!(BlessMode::parse("1"), Some(BlessMode::InPlace));
+    assert_eq!(BlessMode::parse("patch"), Some(BlessMode::Patch));
+    assert_eq!(BlessMode::parse(""), Some(BlessMode::Off));
+    assert_eq!(BlessMode::parse("yes please"), None);
+    assert_eq!(
+        patch_path(Path::new("isa/riscv64/condbr.clif")),
+        Path::new("isa/riscv64/condbr.clif.patch")
+    );
+    assert_eq!(
+        unified_diff("a\nb\n", "a\nc\n", "expected", "actual"),
+        "--- expected\n+++ actual\n@@ -1,2 +1,2 @@\n a\n-b\n+c\n"
+    );
+}
 
-        // Record the difference in line count so future updates can be adjusted
-        // accordingly, and then write the file back out to the filesystem.
-        let old_line_count = old_test.lines().count();
-        let new_line_count = new_test.lines().count();
-        self.line_diff
-            .set(self.line_diff.get() + (new_line_count as isize - old_line_count as isize));
+#[test]
+fn file_update_batches_edits() {
+    let text = "test compile\r\n\r\nfunction %a() {\r\n}\r\n; old a  \r\n\r\nfunction %b() {\r\n}\r\n; run: b\r\n";
+    let update = FileUpdate::in_memory("t.clif", text, BlessMode::InPlace);
+    let location = |line_number| Location { line_number };
+    // Out of order, and two edits to the same function.
+    update
+        .update_at(&location(7), |r| r.push("; new b".to_string()))
+        .unwrap();
+    update
+        .update_at(&location(3), |r| *r = vec!["; new a".to_string()])
+        .unwrap();
+    update
+        .update_at(&location(7), |r| r[0] = "; run: b2".to_string())
+        .unwrap();
+    update.flush().unwrap();
+    assert_eq!(
+        update.updated_text().unwrap(),
+        "test compile\r\n\r\nfunction %a() {\r\n}\r\n; new a\r\nfunction %b() {\r\n}\r\n; run: b2\r\n; new b\r\n"
+    );
+}
 
-        std::fs::write(&self.path, new_test)?;
-        Ok(())
-    }
+#[test]
+fn bless_in_place_writes_no_patch() {
+    let dir = std::env::temp_dir().join(format!("run_one_bless_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("in_place.clif");
+    let text = concat!(
+        "test compile precise-output\n",
+        "target riscv64\n",
+        "\n",
+        "function %f() {\n",
+        "block0:\n",
+        "    return\n",
+        "}\n",
+    );
+    fs::write(&path, text).unwrap();
+
+    let options = Options {
+        bless: BlessMode::InPlace,
+        ..Options::default()
+    };
+    run(&path, &options, None, None).unwrap();
+
+    let blessed = fs::read_to_string(&path).unwrap();
+    assert!(blessed.starts_with(text) && blessed.len() > text.len());
+    assert!(!patch_path(&path).exists());
+    fs::remove_dir_all(&dir).unwrap();
 }
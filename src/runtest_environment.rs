@@ -1,6 +1,6 @@
 use anyhow::anyhow;
 use cranelift_codegen::data_value::DataValue;
-use cranelift_codegen::ir::Type;
+use cranelift_codegen::ir::{ArgumentPurpose, Function, Type};
 use cranelift_reader::parse_heap_command;
 use cranelift_reader::{Comment, HeapCommand};
 
@@ -45,13 +45,41 @@ impl RuntestEnvironment {
         !self.heaps.is_empty()
     }
 
+    /// Check that a test requesting heaps takes the `vmctx` they are passed through.
+    pub fn validate_signature(&self, func: &Function) -> anyhow::Result<()> {
+        let first_arg_is_vmctx = func
+            .signature
+            .params
+            .first()
+            .map(|p| p.purpose == ArgumentPurpose::VMContext)
+            .unwrap_or(false);
+        if !first_arg_is_vmctx && self.is_active() {
+            return Err(anyhow!(concat!(
+                "This test requests a heap, but the first argument is not `i64 vmctx`.\n",
+                "See docs/testing.md for more info on using heap annotations."
+            )));
+        }
+        Ok(())
+    }
+
+    /// Allocates zeroed memory for each heap, in the order they were declared.
+    pub fn allocate_memory(&self) -> Vec<HeapMemory> {
+        self.heaps
+            .iter()
+            .map(|cmd| {
+                let size: u64 = cmd.size.into();
+                vec![0u8; size as usize]
+            })
+            .collect()
+    }
+
     /// Allocates a struct to be injected into the test.
     pub fn runtime_struct(&self) -> RuntestContext {
         RuntestContext::new(&self)
     }
 }
 
-type HeapMemory = Vec<u8>;
+pub type HeapMemory = Vec<u8>;
 
 /// A struct that provides info about the environment to the test
 #[derive(Debug, Clone)]
@@ -83,14 +111,7 @@ pub struct RuntestContext {
 
 impl RuntestContext {
     pub fn new(env: &RuntestEnvironment) -> Self {
-        let heaps: Vec<HeapMemory> = env
-            .heaps
-            .iter()
-            .map(|cmd| {
-                let size: u64 = cmd.size.into();
-                vec![0u8; size as usize]
-            })
-            .collect();
+        let heaps = env.allocate_memory();
 
         let context_struct = heaps
             .iter()
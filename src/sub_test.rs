@@ -1,4 +1,5 @@
-use crate::runone::FileUpdate;
+use crate::error::TestError;
+use crate::runone::{FileUpdate, Options, Timings};
 use anyhow::Context as _;
 
 use cranelift_codegen::ir::Function;
@@ -16,6 +17,15 @@ pub struct Context<'a> {
     /// Additional details about the function from the parser.
     pub details: Details<'a>,
 
+    /// All functions in the test file, including the one being tested.
+    pub functions: &'a [Function],
+
+    /// Where subtests record the time spent compiling and running functions.
+    pub timings: &'a Timings,
+
+    /// How the test file is being run.
+    pub options: &'a Options,
+
     /// Was the function verified before running this test?
     pub verified: bool,
 
@@ -29,6 +39,9 @@ pub struct Context<'a> {
     /// Full path to the file containing the test.
     pub file_path: &'a str,
 
+    /// The full text of the test file.
+    pub source: &'a str,
+
     /// Context used to update the original `file_path` in-place with its test
     /// expectations if so configured in the environment.
     pub file_update: &'a FileUpdate,
@@ -42,6 +55,20 @@ impl<'a> Context<'a> {
             isa: self.isa,
         }
     }
+
+    /// The line number of `text`, which must be a slice of [Context::source] such as the text
+    /// of a comment. Falls back to the function's line for text from anywhere else.
+    pub fn line_of(&self, text: &str) -> usize {
+        let offset = (text.as_ptr() as usize).wrapping_sub(self.source.as_ptr() as usize);
+        if offset > self.source.len() {
+            return self.details.location.line_number;
+        }
+        self.source.as_bytes()[..offset]
+            .iter()
+            .filter(|&&b| b == b'\n')
+            .count()
+            + 1
+    }
 }
 
 /// Common interface for implementations of test commands.
@@ -68,6 +95,12 @@ pub trait SubTest {
         false
     }
 
+    /// If this runner can't run the test, the reason why. Such tests are reported as skipped
+    /// instead of being run.
+    fn skip_reason(&self) -> Option<String> {
+        None
+    }
+
     /// Run this test on `func`.
     fn run(&self, func: Cow<Function>, context: &Context) -> anyhow::Result<()>;
 }
@@ -85,12 +118,11 @@ pub fn run_filecheck(text: &str, context: &Context) -> anyhow::Result<()> {
         let (_, explain) = checker
             .explain(text, NO_VARIABLES)
             .context("filecheck explain failed")?;
-        anyhow::bail!(
+        Err(TestError::Mismatch(format!(
             "filecheck failed for function on line {}:\n{}{}",
-            context.details.location.line_number,
-            checker,
-            explain
-        );
+            context.details.location.line_number, checker, explain
+        ))
+        .into())
     }
 }
 
@@ -110,3 +142,18 @@ pub fn build_filechecker(context: &Context) -> anyhow::Result<Checker> {
     }
     Ok(builder.finish())
 }
+
+/// Look for a directive in a comment string.
+/// The directive is of the form "foo:" and should follow the leading `;` in the comment:
+///
+/// ; dominates: block3 block4
+///
+/// Return the comment text following the directive.
+pub fn match_directive<'a>(comment: &'a str, directive: &str) -> Option<&'a str> {
+    assert!(
+        directive.ends_with(':'),
+        "Directive must include trailing colon"
+    );
+    let text = comment.trim_start_matches(';').trim_start();
+    text.strip_prefix(directive).map(str::trim)
+}
@@ -1,16 +1,20 @@
 //! Test command for testing the code generator pipeline
 //!
 //! The `compile` test command runs each function through the full code generator pipeline
-use crate::sub_test::SubTest;
+use crate::compiler::{compile2, CompilationError};
+use crate::error::TestError;
+use crate::runone::unified_diff;
+use crate::sub_test::{run_filecheck, SubTest};
 
 use crate::{build_backend, Context};
-use anyhow::{bail, Result};
+use anyhow::Result;
 use cranelift_codegen::binemit::CodeInfo;
 use cranelift_codegen::ir;
 use cranelift_reader::{TestCommand, TestOption};
 use log::info;
 use std::borrow::Cow;
 use std::env;
+use std::fmt;
 
 struct TestCompile {
     /// Flag indicating that the text expectation, comments after the function,
@@ -48,16 +52,96 @@ impl SubTest for TestCompile {
     }
 
     fn run(&self, func: Cow<ir::Function>, context: &Context) -> Result<()> {
-        use cranelift_codegen::Context;
-        let mut compiler = Context::for_function(func.clone().into_owned());
-        compiler.want_disasm = true;
-        let reuslt = compiler.compile(build_backend().as_ref()).unwrap();
-        let disasm = reuslt.disasm.clone().unwrap().clone();
-        check_precise_output(disasm.as_str(), context)
+        let isa = build_backend(&context.options.backend_flags);
+        let compiled = compile2(
+            func.into_owned(),
+            isa.as_ref(),
+            context.options.compile_cache.as_deref(),
+        );
+        if let Some(expected) = ExpectedError::parse(context) {
+            return expected.check(compiled, context);
+        }
+        let (_, disasm) = compiled.map_err(TestError::from)?;
+        if self.precise_output {
+            check_precise_output(disasm.as_str(), context)
+        } else {
+            run_filecheck(disasm.as_str(), context)
+        }
     }
 }
 
-const just_update_test: bool = true;
+/// A compile error that a function is declared to fail with, using a
+/// `; compile-error: <kind>` or `; compile-error: <kind>: <message>` comment.
+///
+/// The kind is one of the names returned by [crate::compiler::codegen_error_kind] (`unsupported`,
+/// `impl-limit-exceeded`, ...); the optional message must be contained in the error message.
+struct ExpectedError<'a> {
+    kind: &'a str,
+    message: Option<&'a str>,
+}
+
+impl<'a> ExpectedError<'a> {
+    fn parse(context: &Context<'a>) -> Option<Self> {
+        context.details.comments.iter().find_map(|c| {
+            let directive = c.text.trim_start_matches(';').trim();
+            let rest = directive.strip_prefix("compile-error:")?.trim();
+            Some(match rest.split_once(':') {
+                Some((kind, message)) => ExpectedError {
+                    kind: kind.trim(),
+                    message: Some(message.trim()),
+                },
+                None => ExpectedError {
+                    kind: rest,
+                    message: None,
+                },
+            })
+        })
+    }
+
+    fn check(
+        &self,
+        compiled: Result<(Vec<u8>, String), CompilationError>,
+        context: &Context,
+    ) -> Result<()> {
+        let line = context.details.location.line_number;
+        let err = match compiled {
+            Ok(_) => {
+                return Err(TestError::Mismatch(format!(
+                    "function on line {} was expected to fail with a `{}` compile error, \
+                     but compiled successfully",
+                    line, self
+                ))
+                .into())
+            }
+            Err(err) => err,
+        };
+        let actual = match &err {
+            CompilationError::Pretty { kind, message, .. } => Some((*kind, message)),
+            // Verifier errors are only kept in their pretty form, which includes the message.
+            CompilationError::Verifier(pretty) => Some(("verifier", pretty)),
+            _ => None,
+        };
+        if let Some((kind, message)) = actual {
+            if kind == self.kind && self.message.map_or(true, |m| message.contains(m)) {
+                return Ok(());
+            }
+        }
+        Err(TestError::Mismatch(format!(
+            "function on line {} was expected to fail with a `{}` compile error, but got:\n{}",
+            line, self, err
+        ))
+        .into())
+    }
+}
+
+impl fmt::Display for ExpectedError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.message {
+            Some(message) => write!(f, "{}: {}", self.kind, message),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
 
 fn check_precise_output(text: &str, context: &Context) -> Result<()> {
     let actual = text.lines().collect::<Vec<_>>();
@@ -75,56 +159,48 @@ fn check_precise_output(text: &str, context: &Context) -> Result<()> {
     if actual == expected {
         return Ok(());
     }
-    if just_update_test {
+    if context.file_update.is_enabled() {
         return update_test(&actual, context);
     }
 
     // Otherwise this test has failed, and we can print out as such.
-    bail!(
+    Err(TestError::Mismatch(format!(
         "compilation of function on line {} does not match\n\
          the text expectation\n\
          \n\
-         expected:\n\
-         {:#?}\n\
-         actual:\n\
-         {:#?}\n\
-         \n\
+         {}\n\
          This test assertion can be automatically updated by setting the\n\
-         CRANELIFT_TEST_BLESS=1 environment variable when running this test.
+         CRANELIFT_TEST_BLESS=1 environment variable (or passing --bless) when\n\
+         running this test; CRANELIFT_TEST_BLESS=patch (--bless=patch) writes\n\
+         the update to a .patch file instead.
          ",
         context.details.location.line_number,
-        expected,
-        actual,
-    )
+        unified_diff(
+            &(expected.join("\n") + "\n"),
+            &(actual.join("\n") + "\n"),
+            "expected",
+            "actual"
+        ),
+    ))
+    .into())
 }
 
 fn update_test(output: &[&str], context: &Context) -> Result<()> {
     context
         .file_update
-        .update_at(&context.details.location, |new_test, old_test| {
+        .update_at(&context.details.location, |region| {
+            // The region holds the old test expectation, which is replaced wholesale.
+            region.clear();
+
             // blank newline after the function
-            new_test.push_str("\n");
+            region.push(String::new());
 
             // Splice in the test output
             for output in output {
-                new_test.push_str("; ");
-                new_test.push_str(output);
-                new_test.push_str("\n");
+                region.push(format!("; {}", output));
             }
 
             // blank newline after test assertion
-            new_test.push_str("\n");
-
-            // Drop all remaining commented lines (presumably the old test expectation),
-            // but after we hit a real line then we push all remaining lines.
-            let mut in_next_function = false;
-            for line in old_test {
-                if !in_next_function && (line.trim().is_empty() || line.starts_with(";")) {
-                    continue;
-                }
-                in_next_function = true;
-                new_test.push_str(line);
-                new_test.push_str("\n");
-            }
+            region.push(String::new());
         })
 }
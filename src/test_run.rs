@@ -1,16 +1,17 @@
 //! Test command for running CLIF files and verifying their results
 //!
 //! The `run` test command compiles each function on the host machine and executes it
+//! on the emulator, checking `; run:`, `; print:` and `; trap:` commands.
 
 use crate::compiler::SingleFunctionCompiler;
+use crate::error::{Failures, TestError};
+use crate::interpreter::{parse_trap_commands, Outcome};
+use crate::run_bless::{check_run_commands, parse_run_commands, BlessSource};
 use crate::runtest_environment::RuntestEnvironment;
 use crate::{build_backend, Context, SubTest};
-use cranelift_codegen::ir::ArgumentPurpose;
 use cranelift_codegen::isa::{lookup, lookup_by_name};
 use cranelift_codegen::{ir, settings};
-use cranelift_reader::parse_run_command;
-use cranelift_reader::TestCommand;
-use log::trace;
+use cranelift_reader::{Invocation, TestCommand};
 use std::borrow::Cow;
 use target_lexicon::Architecture;
 
@@ -38,49 +39,77 @@ impl SubTest for TestRun {
     }
 
     fn run(&self, func: Cow<ir::Function>, context: &Context) -> anyhow::Result<()> {
-        let test_env = RuntestEnvironment::parse(&context.details.comments[..])?;
-
-        let isa = build_backend();
+        // Functions always run on the riscv64 emulator, so the ISAs listed in the file are
+        // only used as a filter: the function runs once, for its riscv64 target, instead of
+        // once per target with the same code.
+        let target = context.isa.expect("test run needs an ISA");
+        if !matches!(target.triple().architecture, Architecture::Riscv64(_)) {
+            return Err(TestError::Skipped(format!(
+                "`test run` only runs riscv64 targets, not {}",
+                target.name()
+            ))
+            .into());
+        }
 
-        let mut compiler = SingleFunctionCompiler::new(isa);
-        for comment in context.details.comments.iter() {
-            if let Some(command) = parse_run_command(comment.text, &func.signature)? {
-                trace!("Parsed run command: {}", command);
-                // Note that here we're also explicitly ignoring `context.isa`,
-                // regardless of what's requested. We want to use the native
-                // host ISA no matter what here, so the ISA listed in the file
-                // is only used as a filter to not run into situations like
-                // running x86_64 code on aarch64 platforms.
-                let compiled_fn = compiler.compile(func.clone().into_owned())?;
-                command
-                    .run(|_, run_args| {
-                        let runtime_struct = test_env.runtime_struct();
+        let test_env = RuntestEnvironment::parse(&context.details.comments[..])?;
+        test_env.validate_signature(&func)?;
 
-                        let first_arg_is_vmctx = func
-                            .signature
-                            .params
-                            .first()
-                            .map(|p| p.purpose == ArgumentPurpose::VMContext)
-                            .unwrap_or(false);
+        let mut failures = Failures::default();
+        let commands = parse_run_commands(context, &func.signature, &mut failures);
+        let traps = parse_trap_commands(context, &func.signature, &mut failures);
+        // Compile once for all the run and trap commands, and only if there are any: functions
+        // without them may just be helpers for the others.
+        if commands.is_empty() && traps.is_empty() {
+            return failures.into_result();
+        }
 
-                        if !first_arg_is_vmctx && test_env.is_active() {
-                            return Err(concat!(
-                                "This test requests a heap, but the first argument is not `i64 vmctx`.\n",
-                                "See docs/testing.md for more info on using heap annotations."
-                            ).to_string());
-                        }
+        let isa = build_backend(&context.options.backend_flags);
+        let compiler = SingleFunctionCompiler::new(isa, context.options.compile_cache.clone());
+        let line = context.details.location.line_number;
+        let compiled_fn = match context
+            .timings
+            .compile(|| compiler.compile(func.into_owned()))
+        {
+            Ok(compiled_fn) => compiled_fn,
+            Err(e) => {
+                // Report it along with the run commands that failed to parse.
+                failures.push(context, line, TestError::from(e));
+                return failures.into_result();
+            }
+        };
 
-                        let mut args = Vec::with_capacity(run_args.len());
-                        if test_env.is_active() {
-                            args.push(runtime_struct.pointer(context.isa.unwrap().pointer_type()));
-                        }
-                        args.extend_from_slice(run_args);
+        let call = |invocation: &Invocation| {
+            let runtime_struct = test_env.runtime_struct();
+            let mut args = Vec::with_capacity(invocation.args.len() + 1);
+            if test_env.is_active() {
+                args.push(runtime_struct.pointer(target.pointer_type()));
+            }
+            args.extend_from_slice(&invocation.args);
+            context.timings.execute(|| compiled_fn.call2(&args))
+        };
 
-                        Ok(compiled_fn.call2 (&args))
-                    })
-                    .map_err(|s| anyhow::anyhow!("{}", s))?;
+        for (line, trap) in traps {
+            // A trapping function faults the emulator, which can't tell trap codes apart, so
+            // any fault is taken as the expected trap.
+            let outcome = match call(&trap.invocation) {
+                Ok(values) => Outcome::Return(values),
+                Err(TestError::Emulator(_)) => Outcome::Trap(trap.code),
+                Err(e) => {
+                    failures.push(context, line, e);
+                    continue;
+                }
+            };
+            if let Err(e) = trap.check(&outcome) {
+                failures.push(context, line, e);
             }
         }
-        Ok(())
+
+        check_run_commands(
+            context,
+            commands,
+            BlessSource::Emulator,
+            failures,
+            |invocation, _| Ok(call(invocation)?),
+        )
     }
 }
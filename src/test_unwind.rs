@@ -0,0 +1,161 @@
+//! Test command for verifying the unwind emitted for each function.
+//!
+//! The `unwind` test command compiles each function for the selected ISA, with the flags from
+//! the file's `set` lines, and emits its System V unwind information as an `.eh_frame` section,
+//! which is then decoded and printed for filecheck. This is how the riscv64 prologue/epilogue
+//! unwind descriptions are checked.
+
+use crate::sub_test::{run_filecheck, Context, SubTest};
+use crate::pretty_anyhow_error;
+use anyhow::Result;
+use cranelift_codegen::ir;
+use cranelift_codegen::isa::unwind::UnwindInfo;
+use cranelift_reader::TestCommand;
+use gimli::write::{Address, EhFrame, EndianVec, FrameTable};
+use gimli::RunTimeEndian;
+use std::borrow::Cow;
+
+struct TestUnwind;
+
+pub fn subtest(parsed: &TestCommand) -> Result<Box<dyn SubTest>> {
+    assert_eq!(parsed.command, "unwind");
+    if !parsed.options.is_empty() {
+        anyhow::bail!("No options allowed on {}", parsed);
+    }
+    Ok(Box::new(TestUnwind))
+}
+
+impl SubTest for TestUnwind {
+    fn name(&self) -> &'static str {
+        "unwind"
+    }
+
+    fn is_mutating(&self) -> bool {
+        false
+    }
+
+    fn needs_isa(&self) -> bool {
+        true
+    }
+
+    fn run(&self, func: Cow<ir::Function>, context: &Context) -> Result<()> {
+        let isa = context.isa.expect("unwind needs an ISA");
+        let mut comp_ctx = cranelift_codegen::Context::for_function(func.into_owned());
+
+        if let Err(e) = comp_ctx.compile(isa) {
+            return Err(pretty_anyhow_error(&comp_ctx.func, e));
+        }
+
+        let mut text = String::new();
+        match comp_ctx.create_unwind_info(isa)? {
+            Some(UnwindInfo::SystemV(info)) => {
+                let mut table = FrameTable::default();
+                let cie = isa
+                    .create_systemv_cie()
+                    .expect("the ISA should support a System V CIE");
+
+                let cie_id = table.add_cie(cie);
+                table.add_fde(cie_id, info.to_fde(Address::Constant(0)));
+
+                let mut eh_frame = EhFrame(EndianVec::new(RunTimeEndian::Little));
+                table.write_eh_frame(&mut eh_frame)?;
+
+                systemv::dump(&mut text, &eh_frame.0.into_vec(), isa.pointer_bytes())?;
+            }
+            Some(info) => anyhow::bail!("unexpected unwind info {:?}", info),
+            None => text.push_str("No unwind information.\n"),
+        }
+
+        run_filecheck(&text, context)
+    }
+}
+
+mod systemv {
+    use anyhow::Result;
+    use gimli::read::{CallFrameInstruction, CieOrFde, EhFrame, UnwindSection};
+    use gimli::{BaseAddresses, LittleEndian};
+    use std::collections::HashMap;
+    use std::fmt::Write;
+
+    /// Decode the `.eh_frame` section in `bytes` and print its entries, in the style of
+    /// `objdump --dwarf=frames`.
+    pub fn dump(w: &mut String, bytes: &[u8], address_size: u8) -> Result<()> {
+        let mut eh_frame = EhFrame::new(bytes, LittleEndian);
+        eh_frame.set_address_size(address_size);
+        let bases = BaseAddresses::default();
+
+        let mut cies = HashMap::new();
+        let mut entries = eh_frame.entries(&bases);
+        while let Some(entry) = entries.next()? {
+            match entry {
+                CieOrFde::Cie(cie) => {
+                    writeln!(w, "{:#010x}: CIE", cie.offset())?;
+                    writeln!(w, "        length: {:#010x}", cie.entry_len())?;
+                    writeln!(w, "       version: {:#04x}", cie.version())?;
+                    writeln!(w, "    code_align: {}", cie.code_alignment_factor())?;
+                    writeln!(w, "    data_align: {}", cie.data_alignment_factor())?;
+                    writeln!(w, "   ra_register: {:#x}", cie.return_address_register().0)?;
+                    if let Some(encoding) = cie.fde_address_encoding() {
+                        writeln!(w, "  fde_encoding: {:#02x}", encoding.0)?;
+                    }
+                    let mut instrs = cie.instructions(&eh_frame, &bases);
+                    while let Some(instr) = instrs.next()? {
+                        dump_instruction(w, &instr)?;
+                    }
+                    writeln!(w)?;
+                }
+                CieOrFde::Fde(partial) => {
+                    let fde = partial.parse(|_, bases, o| {
+                        cies.entry(o)
+                            .or_insert_with(|| eh_frame.cie_from_offset(bases, o))
+                            .clone()
+                    })?;
+                    writeln!(w, "{:#010x}: FDE", fde.offset())?;
+                    writeln!(w, "        length: {:#010x}", fde.entry_len())?;
+                    writeln!(w, "   CIE_pointer: {:#010x}", fde.cie().offset())?;
+                    writeln!(w, "    start_addr: {:#018x}", fde.initial_address())?;
+                    writeln!(
+                        w,
+                        "    end_addr:   {:#018x}",
+                        fde.initial_address() + fde.len()
+                    )?;
+                    let mut instrs = fde.instructions(&eh_frame, &bases);
+                    while let Some(instr) = instrs.next()? {
+                        dump_instruction(w, &instr)?;
+                    }
+                    writeln!(w)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn dump_instruction<R: gimli::Reader>(
+        w: &mut String,
+        instr: &CallFrameInstruction<R>,
+    ) -> Result<()> {
+        use CallFrameInstruction::*;
+        write!(w, "    ")?;
+        match instr {
+            AdvanceLoc { delta } => writeln!(w, "DW_CFA_advance_loc ({})", delta)?,
+            DefCfa { register, offset } => {
+                writeln!(w, "DW_CFA_def_cfa (r{}, {})", register.0, offset)?
+            }
+            DefCfaRegister { register } => {
+                writeln!(w, "DW_CFA_def_cfa_register (r{})", register.0)?
+            }
+            DefCfaOffset { offset } => writeln!(w, "DW_CFA_def_cfa_offset ({})", offset)?,
+            Offset {
+                register,
+                factored_offset,
+            } => writeln!(w, "DW_CFA_offset (r{}, {})", register.0, factored_offset)?,
+            SameValue { register } => writeln!(w, "DW_CFA_same_value (r{})", register.0)?,
+            Restore { register } => writeln!(w, "DW_CFA_restore (r{})", register.0)?,
+            RememberState => writeln!(w, "DW_CFA_remember_state")?,
+            RestoreState => writeln!(w, "DW_CFA_restore_state")?,
+            Nop => writeln!(w, "DW_CFA_nop")?,
+            other => writeln!(w, "{:?}", other)?,
+        }
+        Ok(())
+    }
+}